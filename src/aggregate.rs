@@ -0,0 +1,264 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `--aggregate` mode: turns the switcher from a pick-one proxy into a fan-out multiplexer that
+//! exposes the union of identities held by every live agent found in `--agents-dirs`.
+//!
+//! Unlike `proxy` and `policy`, which both treat the agent protocol's payload as opaque bytes (or,
+//! for `policy`, only look at the leading message type), this module actually parses the
+//! `SSH_AGENT_IDENTITIES_ANSWER`/`SSH_AGENTC_SIGN_REQUEST` payloads so it can merge several
+//! backends' identity lists and remember which backend owns which key for the lifetime of one
+//! connection.
+
+use crate::proxy::SetReadTimeout;
+use log::{trace, warn};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::AtomicBool;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// `SSH_AGENT_FAILURE`: the standard rejection reply for a sign request whose key we don't own.
+const SSH_AGENT_FAILURE: u8 = 5;
+
+/// `SSH_AGENTC_REQUEST_IDENTITIES`: list the keys held across every live backend.
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+
+/// `SSH_AGENT_IDENTITIES_ANSWER`: `[u32 count]` followed by `count` repetitions of
+/// `[string key_blob][string comment]`, where `string` is itself `u32`-length-prefixed.
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// `SSH_AGENTC_SIGN_REQUEST`: `[string key_blob]...`; we only need the leading key blob to decide
+/// which backend to route the request to.
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
+/// Maximum size of a single frame we will buffer, mirroring `MAX_FRAME_LEN` in `policy.rs`.
+const MAX_FRAME_LEN: u32 = 256 * 1024;
+
+/// A pre-canned `SSH_AGENT_FAILURE` reply frame: length 1, type `SSH_AGENT_FAILURE`.
+const FAILURE_REPLY: [u8; 5] = [0, 0, 0, 1, SSH_AGENT_FAILURE];
+
+/// Reads one length-prefixed frame from `stream`, returning its raw bytes including the 4-byte
+/// length prefix, or `None` if the peer closed the connection before sending another frame.
+fn read_frame(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match stream.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(format!("Failed to read frame length: {}", e)),
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(format!("Frame length {} is out of range", len));
+    }
+
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend_from_slice(&len_buf);
+    frame.resize(frame.len() + len as usize, 0);
+    stream
+        .read_exact(&mut frame[4..])
+        .map_err(|e| format!("Failed to read frame body: {}", e))?;
+    Ok(Some(frame))
+}
+
+/// Reads a `u32`-length-prefixed byte string out of `payload` starting at `*pos`, advancing `*pos`
+/// past it.
+fn read_string(payload: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    if payload.len() < *pos + 4 {
+        return Err("Truncated length-prefixed string".to_owned());
+    }
+    let len = u32::from_be_bytes(payload[*pos..*pos + 4].try_into().unwrap()) as usize;
+    *pos += 4;
+
+    if payload.len() < *pos + len {
+        return Err("Truncated length-prefixed string".to_owned());
+    }
+    let bytes = payload[*pos..*pos + len].to_owned();
+    *pos += len;
+    Ok(bytes)
+}
+
+/// One identity reported by a backend: its key blob (which doubles as its identity for routing
+/// purposes) and its human-readable comment.
+struct Identity {
+    key_blob: Vec<u8>,
+    comment: Vec<u8>,
+}
+
+/// Parses an `SSH_AGENT_IDENTITIES_ANSWER` frame's payload, following the leading type byte, into
+/// its list of identities.
+fn parse_identities_answer(payload: &[u8]) -> Result<Vec<Identity>> {
+    if payload.len() < 4 {
+        return Err("Identities answer is too short to hold a count".to_owned());
+    }
+    let count = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+    let mut pos = 4;
+
+    let mut identities = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let key_blob = read_string(payload, &mut pos)?;
+        let comment = read_string(payload, &mut pos)?;
+        identities.push(Identity { key_blob, comment });
+    }
+    Ok(identities)
+}
+
+/// Builds an `SSH_AGENT_IDENTITIES_ANSWER` frame listing `identities`.
+fn build_identities_answer(identities: &[Identity]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.push(SSH_AGENT_IDENTITIES_ANSWER);
+    payload.extend_from_slice(&(identities.len() as u32).to_be_bytes());
+    for identity in identities {
+        payload.extend_from_slice(&(identity.key_blob.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&identity.key_blob);
+        payload.extend_from_slice(&(identity.comment.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&identity.comment);
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Queries every backend for its identities, merging the results into one list deduplicated by key
+/// blob (the first backend to report a given blob keeps it), and remembers which backend owns each
+/// blob in `owners` for later `SSH_AGENTC_SIGN_REQUEST` routing.
+fn merge_identities(backends: &mut [UnixStream], owners: &mut HashMap<Vec<u8>, usize>) -> Result<Vec<u8>> {
+    owners.clear();
+    let mut merged = vec![];
+
+    for (i, backend) in backends.iter_mut().enumerate() {
+        backend
+            .write_all(&[0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES])
+            .map_err(|e| format!("Failed to query backend {} for identities: {}", i, e))?;
+
+        let reply = match read_frame(backend)? {
+            Some(reply) => reply,
+            None => {
+                warn!("Backend {} closed the connection while listing identities", i);
+                continue;
+            }
+        };
+        if reply[4] != SSH_AGENT_IDENTITIES_ANSWER {
+            trace!("Backend {} did not answer with an identities list; skipping it", i);
+            continue;
+        }
+
+        match parse_identities_answer(&reply[5..]) {
+            Ok(identities) => {
+                for identity in identities {
+                    if !owners.contains_key(&identity.key_blob) {
+                        owners.insert(identity.key_blob.clone(), i);
+                        merged.push(identity);
+                    }
+                }
+            }
+            Err(e) => warn!("Backend {} sent a malformed identities answer: {}", i, e),
+        }
+    }
+
+    Ok(build_identities_answer(&merged))
+}
+
+/// Serves one `--aggregate` connection: `client` is presented with a single virtual agent whose
+/// identities are the union of every entry in `backends`, and a sign request for a given key is
+/// routed to whichever backend actually advertised it.
+///
+/// `owners` is only populated by an `SSH_AGENTC_REQUEST_IDENTITIES` round, mirroring a real
+/// `ssh-agent`: a client that signs before ever listing identities gets `SSH_AGENT_FAILURE`, same
+/// as it would against a single backend that has never been asked what it holds.
+///
+/// `stop` is checked periodically while waiting for the client's next request (see
+/// `proxy::read_frame_checking_shutdown`), so an idle client under `--aggregate` cannot block a
+/// worker past a requested shutdown.
+pub(crate) fn proxy_aggregated<C: Read + Write + SetReadTimeout>(
+    client: &mut C,
+    backends: &mut [UnixStream],
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut owners: HashMap<Vec<u8>, usize> = HashMap::new();
+
+    while let Some(frame) = crate::proxy::read_frame_checking_shutdown(client, stop)? {
+        let msg_type = frame[4];
+        let payload = &frame[5..];
+
+        match msg_type {
+            SSH_AGENTC_REQUEST_IDENTITIES => {
+                let answer = merge_identities(backends, &mut owners)?;
+                client
+                    .write_all(&answer)
+                    .map_err(|e| format!("Failed to write merged identities to client: {}", e))?;
+            }
+
+            SSH_AGENTC_SIGN_REQUEST => {
+                let mut pos = 0;
+                let key_blob = read_string(payload, &mut pos)?;
+                match owners.get(&key_blob) {
+                    Some(&i) => {
+                        backends[i]
+                            .write_all(&frame)
+                            .map_err(|e| format!("Failed to forward sign request to backend {}: {}", i, e))?;
+                        let reply = read_frame(&mut backends[i])?.ok_or_else(|| {
+                            format!("Backend {} closed the connection before replying to sign request", i)
+                        })?;
+                        client
+                            .write_all(&reply)
+                            .map_err(|e| format!("Failed to write sign reply to client: {}", e))?;
+                    }
+                    None => {
+                        trace!("Sign request for an unknown key blob; replying with failure");
+                        client
+                            .write_all(&FAILURE_REPLY)
+                            .map_err(|e| format!("Failed to write rejection to client: {}", e))?;
+                    }
+                }
+            }
+
+            other => {
+                trace!("Forwarding unrecognized request type {} to the first live backend", other);
+                match backends.first_mut() {
+                    Some(backend) => {
+                        backend
+                            .write_all(&frame)
+                            .map_err(|e| format!("Failed to forward request to backend: {}", e))?;
+                        let reply = read_frame(backend)?
+                            .ok_or_else(|| "Backend closed the connection before replying".to_owned())?;
+                        client
+                            .write_all(&reply)
+                            .map_err(|e| format!("Failed to write reply to client: {}", e))?;
+                    }
+                    None => {
+                        client
+                            .write_all(&FAILURE_REPLY)
+                            .map_err(|e| format!("Failed to write rejection to client: {}", e))?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
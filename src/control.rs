@@ -0,0 +1,208 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! An opt-in control socket for introspecting and reloading a running daemon without attaching a
+//! debugger or scraping logs.
+//!
+//! This speaks a tiny line-based protocol: a client writes one command per line and reads back one
+//! JSON object per line in response.  The supported commands are:
+//!
+//! * `list`: every agent socket currently discovered under the configured agent directories, and
+//!   whether each one answered a liveness probe.
+//! * `status`: uptime, the number of connections currently being proxied, and the agent socket the
+//!   most recent request was served from.
+//! * `reload`: re-read the reloadable configuration, equivalent to sending `SIGHUP`.
+//!
+//! Unlike the agent-proxy socket, this one never speaks the SSH agent wire format, so it is kept
+//! entirely separate from `handle_connection`/`handle_tcp_connection`.
+
+use crate::{find, Config};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// Process-wide state the control socket reports on, updated by the proxy path as connections come
+/// and go.
+pub(crate) struct ControlState {
+    /// When `run` started serving, for the `status` command's `uptime_secs`.
+    start: Instant,
+
+    /// Number of connections currently being proxied, across both the Unix and TCP listeners.
+    active_connections: AtomicUsize,
+
+    /// The agent socket the most recently served connection was proxied to, if any.
+    last_selected: Mutex<Option<PathBuf>>,
+}
+
+impl ControlState {
+    /// Creates a fresh state with no active connections and no recorded history.
+    pub(crate) fn new() -> ControlState {
+        ControlState {
+            start: Instant::now(),
+            active_connections: AtomicUsize::new(0),
+            last_selected: Mutex::new(None),
+        }
+    }
+
+    /// Records that a connection started being handled; the returned guard decrements the count
+    /// again on drop, so the count stays accurate even if the handler returns early on error.
+    pub(crate) fn connection_started(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { state: self }
+    }
+
+    /// Records the agent socket the most recently served connection was proxied to.
+    pub(crate) fn record_selected(&self, selected: Option<&Path>) {
+        *self.last_selected.lock().unwrap() = selected.map(|p| p.to_owned());
+    }
+}
+
+/// RAII guard returned by `ControlState::connection_started`; see there.
+pub(crate) struct ConnectionGuard<'a> {
+    state: &'a ControlState,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.state.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes `s` for embedding as a JSON string, including the surrounding quotes.
+fn json_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats `path` as a JSON string, or `null` if not present.
+fn json_path(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => json_quote(&path.display().to_string()),
+        None => "null".to_owned(),
+    }
+}
+
+/// Handles the `list` command: reports every candidate socket found and its liveness.
+fn handle_list(config: &RwLock<Config>, home: Option<&Path>, uid: libc::uid_t) -> String {
+    let config = config.read().unwrap();
+    let candidates = find::list_candidates(
+        &config.agents_dirs,
+        home,
+        uid,
+        config.probe_timeout,
+        config.scan_concurrency,
+    );
+    let items = candidates
+        .iter()
+        .map(|(path, alive)| format!("{{\"path\":{},\"alive\":{}}}", json_path(Some(path)), alive))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{{\"candidates\":[{}]}}", items)
+}
+
+/// Handles the `status` command: reports uptime, active connections, and the last-selected agent.
+fn handle_status(state: &ControlState) -> String {
+    format!(
+        "{{\"uptime_secs\":{},\"active_connections\":{},\"last_selected\":{}}}",
+        state.start.elapsed().as_secs(),
+        state.active_connections.load(Ordering::Relaxed),
+        json_path(state.last_selected.lock().unwrap().as_deref())
+    )
+}
+
+/// Handles the `reload` command: recomputes the configuration via `reload` and, on success,
+/// replaces `config`'s contents with it -- the same effect `SIGHUP` has.
+fn handle_reload(
+    config: &RwLock<Config>,
+    reload: &(dyn Fn() -> Result<Config> + Send + Sync),
+) -> String {
+    match reload() {
+        Ok(new_config) => {
+            *config.write().unwrap() = new_config;
+            "{\"reloaded\":true}".to_owned()
+        }
+        Err(e) => format!("{{\"reloaded\":false,\"error\":{}}}", json_quote(&e)),
+    }
+}
+
+/// Serves one control-socket connection, reading commands line by line until the client
+/// disconnects.
+pub(crate) fn serve(
+    stream: UnixStream,
+    state: &ControlState,
+    config: &RwLock<Config>,
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    reload: &(dyn Fn() -> Result<Config> + Send + Sync),
+) -> Result<()> {
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .map_err(|e| format!("Cannot clone control socket: {}", e))?,
+    );
+    let mut writer = stream;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read control command: {}", e))?;
+        if n == 0 {
+            break;
+        }
+
+        let response = match line.trim() {
+            "list" => handle_list(config, home, uid),
+            "status" => handle_status(state),
+            "reload" => handle_reload(config, reload),
+            "" => continue,
+            other => format!(
+                "{{\"error\":{}}}",
+                json_quote(&format!("unknown command '{}'", other))
+            ),
+        };
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .map_err(|e| format!("Failed to write control response: {}", e))?;
+    }
+
+    Ok(())
+}
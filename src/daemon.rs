@@ -0,0 +1,177 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Double-fork daemonization and PID file management for `--daemon` mode.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// Byte written to the readiness pipe to indicate the daemon started up successfully.
+const READY_OK: u8 = 1;
+
+/// Byte written to the readiness pipe to indicate the daemon failed to start.
+const READY_FAILED: u8 = 0;
+
+/// Opens `pid_file`, taking an exclusive non-blocking lock on it.
+///
+/// The lock is attached to the underlying open file description and therefore survives `fork()`:
+/// as long as the final daemon process keeps the returned `File` open, no other instance can
+/// acquire the same lock, which is how we detect and refuse a second daemon starting against the
+/// same PID file.
+fn lock_pid_file(pid_file: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(pid_file)
+        .map_err(|e| format!("Cannot open PID file {}: {}", pid_file.display(), e))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(format!(
+            "Another instance is already running with PID file {}",
+            pid_file.display()
+        ));
+    }
+
+    Ok(file)
+}
+
+/// Overwrites `file` with the decimal representation of `pid`.
+fn write_pid_file(file: &mut File, pid: libc::pid_t) -> Result<()> {
+    file.set_len(0).map_err(|e| format!("Cannot truncate PID file: {}", e))?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| format!("Cannot seek PID file: {}", e))?;
+    writeln!(file, "{}", pid).map_err(|e| format!("Cannot write PID file: {}", e))
+}
+
+/// Reads back the PID written to `pid_file` by the final daemon process.
+fn read_pid_file(pid_file: &Path) -> Result<libc::pid_t> {
+    let contents = std::fs::read_to_string(pid_file)
+        .map_err(|e| format!("Cannot read PID file {}: {}", pid_file.display(), e))?;
+    contents
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid PID file contents in {}: {}", pid_file.display(), e))
+}
+
+/// Redirects stdin/stdout/stderr to `/dev/null` now that we have detached from the terminal.
+fn detach_stdio() {
+    if let Ok(dev_null) = OpenOptions::new().read(true).write(true).open("/dev/null") {
+        let fd = dev_null.as_raw_fd();
+        unsafe {
+            libc::dup2(fd, libc::STDIN_FILENO);
+            libc::dup2(fd, libc::STDOUT_FILENO);
+            libc::dup2(fd, libc::STDERR_FILENO);
+        }
+        // `dev_null`'s own fd is closed when it is dropped here; the dup'd descriptors remain.
+    }
+}
+
+/// Writes `byte` to `fd` and closes it, ignoring errors: by this point the child has nothing
+/// useful left to do but report its outcome to whoever is waiting on the other end.
+fn signal(fd: libc::c_int, byte: u8) {
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        libc::close(fd);
+    }
+}
+
+/// Blocks until a readiness byte arrives on `fd` (or the writing end is closed without one),
+/// returning whether the daemon reported success.
+fn wait_for_signal(fd: libc::c_int) -> bool {
+    let mut byte = [0u8; 1];
+    let n = unsafe { libc::read(fd, byte.as_mut_ptr() as *mut libc::c_void, 1) };
+    unsafe { libc::close(fd) };
+    n == 1 && byte[0] == READY_OK
+}
+
+/// Double-forks the current process into the background and writes its final PID to `pid_file`.
+///
+/// This follows the classic daemonization recipe: fork, become a session leader via `setsid` to
+/// detach from the controlling terminal, and fork again so the daemon can never reacquire one.
+/// The original process blocks on a pipe until the final daemon process has locked and written the
+/// PID file (or failed to), then exits -- mirroring how `ssh-agent` returns to the caller only once
+/// it is ready to be used.
+///
+/// Returns the locked PID file handle in the final daemon process.  The original process and the
+/// intermediate fork never return: they call `std::process::exit` directly.
+///
+/// If `on_ready` is given, the original process invokes it with the daemon's PID -- read back from
+/// `pid_file` -- right before exiting, once the daemon has confirmed it is ready.  This is how
+/// callers such as `-s`/`-c` print shell-evaluable output to the original terminal before the
+/// daemon detaches from it.
+pub fn daemonize(pid_file: &Path, on_ready: Option<&dyn Fn(libc::pid_t)>) -> Result<File> {
+    let mut lock = lock_pid_file(pid_file)?;
+
+    let mut pipe_fds = [0; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } != 0 {
+        return Err(format!("Cannot create readiness pipe: {}", io::Error::last_os_error()));
+    }
+    let (read_fd, write_fd) = (pipe_fds[0], pipe_fds[1]);
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("First fork failed: {}", io::Error::last_os_error())),
+        0 => (), // Continue in the intermediate child below.
+        _parent_pid => {
+            unsafe { libc::close(write_fd) };
+            let ready = wait_for_signal(read_fd);
+            if ready {
+                if let Some(callback) = on_ready {
+                    if let Ok(pid) = read_pid_file(pid_file) {
+                        callback(pid);
+                    }
+                }
+            }
+            std::process::exit(if ready { 0 } else { 1 });
+        }
+    }
+
+    unsafe { libc::close(read_fd) };
+    if unsafe { libc::setsid() } == -1 {
+        signal(write_fd, READY_FAILED);
+        std::process::exit(1);
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            signal(write_fd, READY_FAILED);
+            std::process::exit(1);
+        }
+        0 => (), // Continue as the final daemon process below.
+        _intermediate_child_pid => std::process::exit(0),
+    }
+
+    if let Err(e) = write_pid_file(&mut lock, unsafe { libc::getpid() }) {
+        eprintln!("ERROR: {}", e);
+        signal(write_fd, READY_FAILED);
+        std::process::exit(1);
+    }
+
+    detach_stdio();
+    signal(write_fd, READY_OK);
+
+    Ok(lock)
+}
@@ -0,0 +1,240 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Structured logging for discovery and connection events, with optional JSON output.
+//!
+//! The switcher has always emitted free-form `log` lines for these events, and the integration
+//! tests grep for stable phrases within them (e.g. "not a directory", "No socket", "Cannot
+//! connect"); `--log-format json` doesn't change any of that prose, it just gives operators a
+//! second, machine-parseable representation of the same events with stable field names (`event`,
+//! `path`, `reason`, `selected_socket`, `agents_dir`, `client_pid`, `client_addr`,
+//! `bytes_to_agent`, `bytes_to_client`, `failovers`, `duration_secs`, `error`, `timestamp`) to
+//! choose instead.
+//! Each line is independently parseable, so a `connection_closed` record -- the one carrying the
+//! full summary of a finished connection -- can be `tail -f`'d and ingested by another process
+//! without waiting for the whole log file.
+
+use log::{debug, info, trace, warn};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How discovery and connection events are logged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Free-form, human-readable lines.  This is the default and historical behavior.
+    Text,
+
+    /// One JSON object per event, with stable field names, for monitoring scripts.
+    Json,
+}
+
+/// The process-wide log format, set once by `run` during startup.
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Sets the process-wide log format.  Has no effect if called more than once; library consumers
+/// that never call this (e.g. `check`/`status`) get `LogFormat::Text`.
+pub fn set_format(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+fn format() -> LogFormat {
+    *FORMAT.get().unwrap_or(&LogFormat::Text)
+}
+
+/// Seconds since the Unix epoch, for the JSON `timestamp` field.
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Escapes `s` for embedding as a JSON string, including the surrounding quotes.
+fn json_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats `path` as a JSON string, or `null` if not present.
+fn json_path(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => json_quote(&path.display().to_string()),
+        None => "null".to_owned(),
+    }
+}
+
+/// Logs that the candidate socket at `path` was rejected during discovery.
+///
+/// `text` is the existing human-readable message describing why, unchanged from before this
+/// module existed; `reason` is the same information in a form suitable for the JSON `reason`
+/// field.
+pub(crate) fn log_rejected(text: &str, path: &Path, reason: &str) {
+    match format() {
+        LogFormat::Text => trace!("{}", text),
+        LogFormat::Json => trace!(
+            "{{\"event\":\"candidate_rejected\",\"path\":{},\"reason\":{},\"timestamp\":{}}}",
+            json_path(Some(path)),
+            json_quote(reason),
+            timestamp()
+        ),
+    }
+}
+
+/// Logs that no live candidate was found in `dir`.
+pub(crate) fn log_no_socket(text: &str, dir: &Path) {
+    match format() {
+        LogFormat::Text => debug!("{}", text),
+        LogFormat::Json => debug!(
+            "{{\"event\":\"no_socket\",\"path\":{},\"timestamp\":{}}}",
+            json_path(Some(dir)),
+            timestamp()
+        ),
+    }
+}
+
+/// Logs that `path` was selected as a valid agent socket.
+pub(crate) fn log_selected(text: &str, path: &Path) {
+    match format() {
+        LogFormat::Text => info!("{}", text),
+        LogFormat::Json => info!(
+            "{{\"event\":\"agent_selected\",\"selected_socket\":{},\"timestamp\":{}}}",
+            json_path(Some(path)),
+            timestamp()
+        ),
+    }
+}
+
+/// Logs that a client connection was handled, forwarding to `selected_socket` (or dropped, if
+/// `None`, because no agent was found) and identifying the client by `client_pid` when known --
+/// which is only possible for local Unix socket clients, not ones connecting over `--listen-tcp`.
+pub(crate) fn log_connection_served(selected_socket: Option<&Path>, client_pid: Option<libc::pid_t>) {
+    match format() {
+        LogFormat::Text => info!(
+            "Serving client (pid {}) via agent socket {}",
+            client_pid.map(|p| p.to_string()).unwrap_or_else(|| "unknown".to_owned()),
+            selected_socket.map(|p| p.display().to_string()).unwrap_or_else(|| "none".to_owned())
+        ),
+        LogFormat::Json => info!(
+            "{{\"event\":\"connection_served\",\"selected_socket\":{},\"client_pid\":{},\"timestamp\":{}}}",
+            json_path(selected_socket),
+            client_pid.map(|p| p.to_string()).unwrap_or_else(|| "null".to_owned()),
+            timestamp()
+        ),
+    }
+}
+
+/// Everything `log_connection_closed` reports about one finished connection; see there.
+pub(crate) struct ConnectionSummary<'a> {
+    /// Agent socket the connection was proxied to, or `None` if none was ever selected.
+    pub(crate) selected_socket: Option<&'a Path>,
+
+    /// Client's PID, when known -- only possible for local Unix socket clients.
+    pub(crate) client_pid: Option<libc::pid_t>,
+
+    /// Client's remote address, when known -- only possible for `--listen-tcp`/`--listen` clients.
+    pub(crate) client_addr: Option<&'a str>,
+
+    /// Bytes forwarded as `(client_to_agent, agent_to_client)`, or `None` if the switcher never saw
+    /// the data -- e.g. the connection was handed off to the client via `fdpass` -- or if the
+    /// connection ended in an error, in which case the counts are not kept.
+    pub(crate) bytes: Option<(u64, u64)>,
+
+    /// How many times `--failover` swapped the active backend out for another one during this
+    /// connection; always `0` outside of `--failover` mode, and also `0` when the connection ended
+    /// in an error, in which case the count is not kept.
+    pub(crate) failovers: usize,
+
+    /// How long the connection was held open, from accept to close.
+    pub(crate) duration: Duration,
+
+    /// The error the connection ended with, if it didn't close cleanly.
+    pub(crate) error: Option<&'a str>,
+}
+
+/// Logs that a connection finished, with the full picture of how it went: which agent socket (and
+/// directory) served it, how long it took, how many bytes went each way, and whether it ended in
+/// error.  Unlike `log_connection_served`, which fires as soon as an agent is selected, this fires
+/// once the connection is fully closed, so it is the record to `tail -f` and ingest for auditing
+/// which agent served which request.
+pub(crate) fn log_connection_closed(summary: &ConnectionSummary) {
+    let agents_dir = summary.selected_socket.and_then(Path::parent);
+
+    match format() {
+        LogFormat::Text => {
+            let client = match (summary.client_pid, summary.client_addr) {
+                (Some(pid), _) => format!("pid {}", pid),
+                (None, Some(addr)) => addr.to_owned(),
+                (None, None) => "unknown".to_owned(),
+            };
+            let bytes = match summary.bytes {
+                Some((to_agent, to_client)) => {
+                    format!("{} bytes to agent, {} bytes to client", to_agent, to_client)
+                }
+                None => "byte counts unavailable".to_owned(),
+            };
+            let socket = summary
+                .selected_socket
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| "none".to_owned());
+            let failovers = if summary.failovers > 0 {
+                format!(", {} failover(s)", summary.failovers)
+            } else {
+                String::new()
+            };
+            match summary.error {
+                Some(e) => warn!(
+                    "Connection from {} via agent socket {} failed after {:.3}s ({}{}): {}",
+                    client, socket, summary.duration.as_secs_f64(), bytes, failovers, e
+                ),
+                None => info!(
+                    "Connection from {} via agent socket {} closed cleanly after {:.3}s ({}{})",
+                    client, socket, summary.duration.as_secs_f64(), bytes, failovers
+                ),
+            }
+        }
+        LogFormat::Json => info!(
+            "{{\"event\":\"connection_closed\",\"selected_socket\":{},\"agents_dir\":{},\
+             \"client_pid\":{},\"client_addr\":{},\"bytes_to_agent\":{},\"bytes_to_client\":{},\
+             \"failovers\":{},\"duration_secs\":{:.3},\"error\":{},\"timestamp\":{}}}",
+            json_path(summary.selected_socket),
+            json_path(agents_dir),
+            summary.client_pid.map(|p| p.to_string()).unwrap_or_else(|| "null".to_owned()),
+            summary.client_addr.map(json_quote).unwrap_or_else(|| "null".to_owned()),
+            summary.bytes.map(|(n, _)| n.to_string()).unwrap_or_else(|| "null".to_owned()),
+            summary.bytes.map(|(_, n)| n.to_string()).unwrap_or_else(|| "null".to_owned()),
+            summary.failovers,
+            summary.duration.as_secs_f64(),
+            summary.error.map(json_quote).unwrap_or_else(|| "null".to_owned()),
+            timestamp()
+        ),
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `--failover` mode: like `proxy::proxy_request`, but reselects and reconnects to another live
+//! backend if the current one closes the connection or errors out, instead of letting the client's
+//! connection die with it.
+//!
+//! Like `policy`, this parses the agent wire format -- a 4-byte big-endian length followed by a
+//! 1-byte message type and its payload -- but only to find message boundaries; it never looks at
+//! the message type itself. A backend is only ever swapped out between a completed request and its
+//! reply and the next request, never in the middle of forwarding one, so a frame is never split
+//! across two backends.
+
+use crate::proxy::SetReadTimeout;
+use log::trace;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// Maximum size of a single backend reply frame we will buffer, mirroring `MAX_FRAME_LEN` in
+/// `policy.rs`/`aggregate.rs`.
+const MAX_FRAME_LEN: u32 = 256 * 1024;
+
+/// Returns whether `e` is the error `std::io::Read::read`/`read_exact` reports when a socket's
+/// configured read timeout elapses before any data arrives -- `WouldBlock` on Linux, `TimedOut` on
+/// other platforms -- as opposed to the backend connection actually failing; mirrors
+/// `proxy::is_timeout`.
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// Sends `frame` to `agent` and reads its reply in full.
+///
+/// A connection error or EOF while doing so means `agent` is no longer usable; a plain read
+/// timeout does not, and is reported separately (see `proxy_with_failover`) since the backend may
+/// simply be slow -- a hardware token awaiting a touch, or a loaded agent -- rather than dead, and
+/// `frame` has already been fully delivered to it by the time the timeout fires.
+fn forward_one(agent: &mut UnixStream, frame: &[u8]) -> std::io::Result<Vec<u8>> {
+    agent.write_all(frame)?;
+
+    let mut len_buf = [0; 4];
+    agent.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Reply length {} is out of range", len),
+        ));
+    }
+
+    let mut reply = vec![0; 4 + len as usize];
+    reply[..4].copy_from_slice(&len_buf);
+    agent.read_exact(&mut reply[4..])?;
+    Ok(reply)
+}
+
+/// Everything `proxy_with_failover` reports about how a connection went, for
+/// `event::log_connection_closed` to include in its summary.
+pub(crate) struct FailoverResult {
+    /// Bytes written to whichever backend(s) served the connection.
+    pub(crate) bytes_to_agent: u64,
+
+    /// Bytes written back to the client.
+    pub(crate) bytes_to_client: u64,
+
+    /// How many times the active backend was swapped out for another one mid-connection.
+    pub(crate) failovers: usize,
+}
+
+/// Forwards `client`'s requests to `agent`, reselecting to another live backend -- by calling
+/// `reselect` -- whenever the active one closes the connection or otherwise errors out.
+///
+/// A plain read timeout is deliberately *not* treated as a failover trigger: `frame` has already
+/// been written to `agent` by the time `read_timeout` can fire, so reselecting and resending it
+/// would replay a request the backend may already be acting on -- duplicate signatures, duplicate
+/// hardware-token touch prompts, or double-applying a non-idempotent `SSH_AGENTC_ADD_IDENTITY`/
+/// `SSH_AGENTC_REMOVE_IDENTITY`/`SSH_AGENTC_REMOVE_ALL_IDENTITIES`/`SSH_AGENTC_LOCK`/
+/// `SSH_AGENTC_UNLOCK`. A backend that merely hasn't answered within `read_timeout` instead ends
+/// the connection with an error, the same as running out of backends to fail over to.
+///
+/// `reselect` is expected to re-run the same backend-selection logic that chose `agent` in the
+/// first place (see `find::find_socket`) and returns `None` once no live candidate remains, which
+/// ends the connection with an error instead of retrying forever.
+///
+/// `stop` is checked periodically while waiting for the client's next request (see
+/// `proxy::read_frame_checking_shutdown`), so an idle client under `--failover` cannot block a
+/// worker past a requested shutdown.
+pub(crate) fn proxy_with_failover<C: Read + Write + SetReadTimeout>(
+    client: &mut C,
+    mut agent: UnixStream,
+    read_timeout: Duration,
+    mut reselect: impl FnMut() -> Option<UnixStream>,
+    stop: &AtomicBool,
+) -> Result<FailoverResult> {
+    let mut bytes_to_agent: u64 = 0;
+    let mut bytes_to_client: u64 = 0;
+    let mut failovers: usize = 0;
+
+    agent
+        .set_read_timeout(Some(read_timeout))
+        .map_err(|e| format!("Failed to set read timeout on agent socket: {}", e))?;
+
+    while let Some(frame) = crate::proxy::read_frame_checking_shutdown(client, stop)? {
+        let reply = loop {
+            match forward_one(&mut agent, &frame) {
+                Ok(reply) => break reply,
+                Err(e) if is_timeout(&e) => {
+                    return Err(format!(
+                        "Backend did not reply within the failover read timeout of {:?}; refusing to \
+                         resend a request it may already have received: {}",
+                        read_timeout, e
+                    ));
+                }
+                Err(e) => {
+                    failovers += 1;
+                    trace!("Backend failed mid-connection ({}); failing over (attempt {})", e, failovers);
+                    agent = reselect().ok_or_else(|| {
+                        format!("No live backend available after {} failover(s): {}", failovers, e)
+                    })?;
+                    agent.set_read_timeout(Some(read_timeout)).map_err(|e| {
+                        format!("Failed to set read timeout on agent socket: {}", e)
+                    })?;
+                }
+            }
+        };
+
+        bytes_to_agent += frame.len() as u64;
+        client.write_all(&reply).map_err(|e| format!("Failed to write reply to client: {}", e))?;
+        bytes_to_client += reply.len() as u64;
+    }
+
+    Ok(FailoverResult { bytes_to_agent, bytes_to_client, failovers })
+}
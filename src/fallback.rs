@@ -0,0 +1,126 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Connect-or-spawn fallback to a local agent when no forwarded agent is available.
+
+use crate::error;
+use std::fs;
+use std::io::Result;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// How to obtain a fallback agent when no forwarded agent can be found.
+pub enum FallbackConfig {
+    /// Spawn a local `ssh-agent` the first time it is needed, placing its socket under `dir`.
+    SpawnSshAgent { dir: PathBuf },
+
+    /// Connect to an already-running agent at this fixed socket path.
+    ExternalSocket { path: PathBuf },
+}
+
+/// A local `ssh-agent` subprocess spawned to stand in for a forwarded agent.
+///
+/// `ssh-agent` daemonizes itself, so we track the PID it reports on stdout rather than the PID of
+/// the process we `spawn`, mirroring how the test harness already tracks real agents for cleanup.
+struct SpawnedAgent {
+    socket_path: PathBuf,
+    agent_pid: libc::pid_t,
+}
+
+impl SpawnedAgent {
+    fn spawn(socket_path: PathBuf) -> Result<SpawnedAgent> {
+        let output = Command::new("ssh-agent")
+            .arg("-a")
+            .arg(&socket_path)
+            .output()
+            .map_err(|e| error!(e.kind(), "Failed to spawn fallback ssh-agent: {}", e))?;
+        if !output.status.success() {
+            return Err(error!(
+                std::io::ErrorKind::Other,
+                "fallback ssh-agent exited with {}",
+                output.status
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let agent_pid: libc::pid_t = stdout
+            .lines()
+            .find(|l| l.starts_with("SSH_AGENT_PID="))
+            .and_then(|l| l.strip_prefix("SSH_AGENT_PID="))
+            .and_then(|s| s.split(';').next())
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or_else(|| {
+                error!(
+                    std::io::ErrorKind::Other,
+                    "Could not parse SSH_AGENT_PID from fallback ssh-agent output"
+                )
+            })?;
+
+        Ok(SpawnedAgent { socket_path, agent_pid })
+    }
+
+    fn connect(&self) -> Result<UnixStream> {
+        UnixStream::connect(&self.socket_path)
+    }
+}
+
+impl Drop for SpawnedAgent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.agent_pid, libc::SIGTERM);
+        }
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+/// A fallback agent source that is realized lazily, on the first connection attempt, and then
+/// cached for the lifetime of the switcher.
+pub struct LazyFallback {
+    config: FallbackConfig,
+    spawned: Mutex<Option<SpawnedAgent>>,
+}
+
+impl LazyFallback {
+    /// Creates a new lazy fallback from `config`.  Nothing is spawned or connected to yet.
+    pub fn new(config: FallbackConfig) -> LazyFallback {
+        LazyFallback { config, spawned: Mutex::new(None) }
+    }
+
+    /// Connects to the fallback agent, spawning it first if this is a `SpawnSshAgent` fallback and
+    /// no instance has been spawned yet.
+    pub fn connect(&self) -> Result<UnixStream> {
+        match &self.config {
+            FallbackConfig::ExternalSocket { path } => UnixStream::connect(path),
+            FallbackConfig::SpawnSshAgent { dir } => {
+                let mut spawned = self.spawned.lock().unwrap();
+                if spawned.is_none() {
+                    let socket_path = dir.join(format!("fallback-agent.{}.sock", std::process::id()));
+                    *spawned = Some(SpawnedAgent::spawn(socket_path)?);
+                }
+                spawned.as_ref().expect("just populated above").connect()
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A fast path that hands the real agent socket's file descriptor directly to a same-host client
+//! via an `SCM_RIGHTS` ancillary message, the same technique Mercurial's chg uses in `sendfds`,
+//! instead of relaying every byte through `proxy::proxy_request`.
+//!
+//! A real `ssh` client has no notion of this extension and must never be surprised by it, so it is
+//! entirely opt-in from the client's side: before doing anything else, we peek -- without consuming
+//! -- at the first byte a connecting client has sent.  Only a client that knows about this
+//! extension sends `CAP_REQUEST` as that very first byte; a legitimate agent request frame's
+//! leading length byte is essentially always `0x00` and so never matches, and is left completely
+//! untouched for `proxy::proxy_request` to read normally.
+
+use crate::proxy::SHUTDOWN_POLL_INTERVAL;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// First byte a fd-passing-aware client sends to request the fast path.  Chosen because it can
+/// never be the leading length byte of a legitimate agent request frame: those are at most a few
+/// kilobytes, so the high byte of their 4-byte big-endian length is always `0x00`.
+const CAP_REQUEST: u8 = 0xff;
+
+/// Byte the server writes as ordinary data alongside the `SCM_RIGHTS` ancillary message, so that
+/// the client's `recvmsg(2)` call returns a nonzero byte count together with the fd.
+const CAP_ACK: u8 = 0x01;
+
+/// Peeks at `client`'s first byte without consuming it, returning whether it is `CAP_REQUEST`.
+///
+/// This is the very first thing done with a freshly-accepted connection, before
+/// `proxy::proxy_request` or any of its shutdown-aware siblings get a chance to run, so it has to
+/// do its own waiting: the read timeout installed here is capped at `SHUTDOWN_POLL_INTERVAL` and
+/// `stop` is checked on every tick, the same pattern `proxy::read_fully_checking_shutdown` uses,
+/// so that a client which connects and then sends nothing cannot block a pool worker -- and in
+/// turn `run`'s `thread::scope` join -- past a requested shutdown.
+fn peer_requests_fd_passing(client: &UnixStream, stop: &AtomicBool) -> Result<bool> {
+    client
+        .set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))
+        .map_err(|e| format!("Failed to set read timeout on client socket: {}", e))?;
+
+    loop {
+        let mut byte = [0u8; 1];
+        let n = unsafe {
+            libc::recv(client.as_raw_fd(), byte.as_mut_ptr() as *mut libc::c_void, 1, libc::MSG_PEEK)
+        };
+        if n >= 0 {
+            return Ok(n == 1 && byte[0] == CAP_REQUEST);
+        }
+
+        let e = io::Error::last_os_error();
+        if !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+            return Err(format!("Failed to peek at client's first byte: {}", e));
+        }
+        if stop.load(Ordering::Relaxed) {
+            return Err("Shutting down".to_owned());
+        }
+    }
+}
+
+/// Sends `fd` to the peer on `socket_fd` as an `SCM_RIGHTS` ancillary message, along with a single
+/// `CAP_ACK` byte of regular data.
+fn send_fd(socket_fd: RawFd, fd: RawFd) -> Result<()> {
+    let mut iov =
+        libc::iovec { iov_base: &CAP_ACK as *const u8 as *mut libc::c_void, iov_len: 1 };
+
+    // Sized to hold exactly one fd's worth of ancillary data; see CMSG_SPACE(3).
+    let mut cmsg_buf =
+        vec![0u8; unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize }];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    // Safety: cmsg_buf is sized by CMSG_SPACE for exactly one RawFd, so CMSG_FIRSTHDR always
+    // returns a valid, appropriately-aligned pointer within it to write the header and fd into.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::copy_nonoverlapping(
+            &fd as *const RawFd as *const u8,
+            libc::CMSG_DATA(cmsg),
+            std::mem::size_of::<RawFd>(),
+        );
+    }
+
+    let rc = unsafe { libc::sendmsg(socket_fd, &msg, 0) };
+    if rc < 0 {
+        return Err(format!(
+            "Failed to send agent fd via SCM_RIGHTS: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+/// If `client` just asked for the fd-passing fast path, consumes its single-byte request, sends
+/// `agent`'s file descriptor over `client` via `SCM_RIGHTS`, and returns `true`.  Returns `false` --
+/// having consumed nothing from `client` -- if the client doesn't support this extension, so the
+/// caller can fall back to `proxy::proxy_request` untouched.
+///
+/// `stop` is checked while waiting to find out which case applies (see
+/// `peer_requests_fd_passing`), so an idle client cannot block a worker past a requested shutdown.
+pub(crate) fn try_offer(client: &mut UnixStream, agent: &UnixStream, stop: &AtomicBool) -> Result<bool> {
+    if !peer_requests_fd_passing(client, stop)? {
+        return Ok(false);
+    }
+
+    let mut discard = [0u8; 1];
+    client
+        .read_exact(&mut discard)
+        .map_err(|e| format!("Failed to consume capability request byte: {}", e))?;
+
+    send_fd(client.as_raw_fd(), agent.as_raw_fd())
+}
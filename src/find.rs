@@ -23,11 +23,16 @@
 
 //! Utilities to find the correct SSH agent socket.
 
-use log::{debug, info, trace};
-use std::io::{ErrorKind, Result};
+use crate::event;
+use log::{debug, trace};
+use std::io::{self, ErrorKind, Read, Result, Write};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 use std::{fs, path::PathBuf};
 
 /// Syntactic sugar to instantiate an error.
@@ -42,8 +47,58 @@ macro_rules! error {
     };
 }
 
+/// Wire-level message type for `SSH_AGENTC_REQUEST_IDENTITIES`.
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+
+/// Wire-level message type for `SSH_AGENT_IDENTITIES_ANSWER`.
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Wire-level message type for `SSH_AGENT_FAILURE`.
+const SSH_AGENT_FAILURE: u8 = 5;
+
+/// Maximum size we are willing to read for a probe reply, to avoid a misbehaving peer making us
+/// allocate an unbounded buffer.
+const MAX_PROBE_REPLY_LEN: u32 = 256 * 1024;
+
+/// Sends a `SSH_AGENTC_REQUEST_IDENTITIES` probe over `socket` and confirms that the peer replies
+/// with something that looks like a real SSH agent.
+///
+/// `timeout` bounds how long we wait for the peer to answer; a socket that accepts connections but
+/// never (or too slowly) replies is not considered a valid agent.
+fn probe_agent(socket: &mut UnixStream, timeout: Duration) -> Result<()> {
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+
+    socket.write_all(&[0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES])?;
+
+    let mut len_buf = [0; 4];
+    socket.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_PROBE_REPLY_LEN {
+        return Err(error!(ErrorKind::InvalidData, "Probe reply length {} is out of range", len));
+    }
+
+    let mut reply = vec![0; len as usize];
+    socket.read_exact(&mut reply)?;
+
+    match reply[0] {
+        SSH_AGENT_IDENTITIES_ANSWER | SSH_AGENT_FAILURE => Ok(()),
+        other => Err(error!(ErrorKind::InvalidData, "Unexpected probe reply type {}", other)),
+    }
+}
+
 /// Attempts to open the socket `path`.
-fn try_open(path: &Path) -> Result<UnixStream> {
+///
+/// If `probe_timeout` is `Some`, the candidate must also answer a `SSH_AGENTC_REQUEST_IDENTITIES`
+/// probe within the given timeout to be considered valid; this weeds out stale sockets that still
+/// accept connections but are not backed by a live agent.  Passing `None` restores the old
+/// connect-only behavior.
+///
+/// The connection used for the probe is never the one returned: once a candidate answers, we
+/// close that connection and open a brand new one for the caller to actually forward requests
+/// over, so the probe's own request/reply never risks leaving stray bytes on the connection the
+/// client ends up talking through.
+fn try_open(path: &Path, probe_timeout: Option<Duration>) -> Result<UnixStream> {
     let name = path.file_name().expect(
         "The path comes from joining a directory to one of its entries, so it must have a name",
     );
@@ -68,18 +123,275 @@ fn try_open(path: &Path) -> Result<UnixStream> {
         return Err(error!(ErrorKind::InvalidInput, "Path is not a socket"));
     }
 
-    let socket = UnixStream::connect(&path)
+    let mut socket = UnixStream::connect(&path)
         .map_err(|e| error!(e.kind(), "Cannot connect to socket: {}", e))?;
 
+    if let Some(timeout) = probe_timeout {
+        probe_agent(&mut socket, timeout)
+            .map_err(|e| error!(e.kind(), "Candidate did not answer a valid agent probe: {}", e))?;
+
+        // The probe connection has now exchanged a request/reply pair of its own; reconnect from
+        // scratch rather than handing it to the caller, so forwarding always starts from a
+        // connection the probe never touched.
+        drop(socket);
+        socket = UnixStream::connect(&path)
+            .map_err(|e| error!(e.kind(), "Cannot reconnect to socket after probing: {}", e))?;
+    }
+
     Ok(socket)
 }
 
+/// Returns every path directly inside `dir`, logging (at `debug`) and skipping any entry that
+/// can't be read; does not validate that the entries are useful candidates in any way.
+fn dir_entries(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to read directory entries in {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+
+    entries
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                debug!("Failed to read directory entry in {}: {}", dir.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Runs `probe` against every path in `candidates` using up to `concurrency` worker threads,
+/// streaming each `(original index, outcome)` pair back over the returned channel as soon as it is
+/// ready -- in whatever order workers finish, not necessarily `candidates`' order.
+///
+/// This is the shared pool behind `probe_concurrently` (stop at the first success),
+/// `probe_all_concurrently` (collect a verdict for every candidate), and
+/// `probe_all_connect_concurrently` (collect a connection for every live candidate); each just
+/// drains the returned receiver differently.
+///
+/// Workers are plain `thread::spawn`, not a `thread::scope` pool, specifically so that a consumer
+/// which stops draining early -- as `probe_concurrently` does once it has its first success --
+/// can return without waiting for the rest: dropping the receiver makes every worker's next
+/// `Sender::send` fail, so a worker not yet probing a new candidate stops there, and one already
+/// mid-probe simply finishes and exits on its own instead of blocking anyone.
+fn spawn_probe_pool(
+    candidates: Vec<PathBuf>,
+    concurrency: usize,
+    probe: impl Fn(&Path) -> Result<UnixStream> + Send + Sync + 'static,
+) -> mpsc::Receiver<(usize, Result<UnixStream>)> {
+    let (tx, rx) = mpsc::channel();
+    if candidates.is_empty() {
+        return rx;
+    }
+
+    let probe = Arc::new(probe);
+    let next_index = Arc::new(AtomicUsize::new(0));
+    let candidates = Arc::new(candidates);
+    let num_workers = concurrency.max(1).min(candidates.len());
+
+    for _ in 0..num_workers {
+        let probe = Arc::clone(&probe);
+        let next_index = Arc::clone(&next_index);
+        let candidates = Arc::clone(&candidates);
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let i = next_index.fetch_add(1, Ordering::Relaxed);
+            if i >= candidates.len() {
+                break;
+            }
+            let outcome = probe(&candidates[i]);
+            if tx.send((i, outcome)).is_err() {
+                // The receiver was dropped -- some earlier result already satisfied the caller --
+                // so there is no point probing any further candidates on this worker.
+                break;
+            }
+        });
+    }
+
+    rx
+}
+
+/// Probes every item in `candidates` concurrently using up to `concurrency` worker threads and
+/// returns the `UnixStream` for every candidate that validated successfully, in the same order as
+/// `candidates`.
+///
+/// Unlike `probe_concurrently`, this does not stop at the first success: it is used by
+/// `--aggregate` mode, which needs a connection to every live backend instead of just one.
+fn probe_all_connect_concurrently(
+    candidates: Vec<PathBuf>,
+    concurrency: usize,
+    probe: impl Fn(&Path) -> Result<UnixStream> + Send + Sync + 'static,
+) -> Vec<UnixStream> {
+    let len = candidates.len();
+    let rx = spawn_probe_pool(candidates, concurrency, probe);
+
+    let mut sockets: Vec<Option<UnixStream>> = (0..len).map(|_| None).collect();
+    for (i, outcome) in rx {
+        if let Ok(socket) = outcome {
+            sockets[i] = Some(socket);
+        }
+    }
+    sockets.into_iter().flatten().collect()
+}
+
+/// Connects to every live candidate socket directly inside `dir`, probing concurrently up to
+/// `concurrency` workers at a time.
+fn connect_all_in_subdir(dir: &Path, probe_timeout: Option<Duration>, concurrency: usize) -> Vec<UnixStream> {
+    let mut candidates = dir_entries(dir);
+    candidates.sort();
+    probe_all_connect_concurrently(candidates, concurrency, move |candidate| {
+        try_open(candidate, probe_timeout)
+    })
+}
+
+/// Validates that `dir` looks like an sshd-created session subdirectory owned by `uid`, same as
+/// `try_shared_subdir`, then connects to every live candidate within it instead of just the first.
+fn connect_all_in_shared_subdir(
+    dir: &Path,
+    uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<UnixStream> {
+    let name = match dir.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return vec![],
+    };
+    if !name.starts_with("ssh-") {
+        return vec![];
+    }
+
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            trace!("Ignoring {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+    if metadata.uid() != uid {
+        trace!(
+            "Ignoring {}: owned by {}, not the current user {}",
+            dir.display(),
+            metadata.uid(),
+            uid
+        );
+        return vec![];
+    }
+
+    connect_all_in_subdir(dir, probe_timeout, concurrency)
+}
+
+/// Connects to every live candidate across all of `dir`'s session subdirectories.
+fn connect_all_in_shared_dir(
+    dir: &Path,
+    our_uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<UnixStream> {
+    let mut subdirs: Vec<PathBuf> =
+        dir_entries(dir).into_iter().filter(|path| path.is_dir()).collect();
+    subdirs.sort();
+
+    subdirs
+        .into_iter()
+        .flat_map(|subdir| connect_all_in_shared_subdir(&subdir, our_uid, probe_timeout, concurrency))
+        .collect()
+}
+
+/// Connects to every live agent backend across `dirs`, for `--aggregate` mode's fan-out.
+///
+/// Unlike `find_socket`, which stops at the first live candidate found, this returns all of them
+/// so the caller can merge their identities and route signing requests to whichever backend
+/// actually holds the requested key.
+pub(super) fn connect_all_live(
+    dirs: &[PathBuf],
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<UnixStream> {
+    let mut sockets = vec![];
+
+    for dir in dirs {
+        if let Some(home) = home {
+            if dir.starts_with(home) {
+                sockets.extend(connect_all_in_subdir(dir, probe_timeout, concurrency));
+            }
+        }
+
+        sockets.extend(connect_all_in_shared_dir(dir, uid, probe_timeout, concurrency));
+    }
+
+    sockets
+}
+
+/// Probes every path in `candidates` concurrently using up to `concurrency` worker threads and
+/// returns the `UnixStream` for the first one to validate successfully, ignoring (and logging via
+/// `on_reject`) the ones that do not.
+///
+/// Outcomes arrive over an `mpsc` channel in whatever order workers finish, but a success is only
+/// returned once every candidate before it in `candidates` has been rejected; at that point this
+/// function returns without waiting for the candidates after it that are still being probed (see
+/// `spawn_probe_pool`). Ties are therefore broken by position in `candidates`, the same as a serial
+/// scan would, so callers that pre-sort `candidates` get the lowest sorted path as the winner.
+fn probe_concurrently(
+    candidates: Vec<PathBuf>,
+    concurrency: usize,
+    probe: impl Fn(&Path) -> Result<UnixStream> + Send + Sync + 'static,
+    on_reject: impl Fn(&Path, io::Error),
+) -> Option<UnixStream> {
+    let paths = candidates.clone();
+    let rx = spawn_probe_pool(candidates, concurrency, probe);
+
+    let mut pending: Vec<Option<Result<UnixStream>>> = (0..paths.len()).map(|_| None).collect();
+    let mut next = 0;
+    for (i, outcome) in rx {
+        pending[i] = Some(outcome);
+        while next < pending.len() {
+            match pending[next].take() {
+                Some(Ok(socket)) => return Some(socket),
+                Some(Err(e)) => on_reject(&paths[next], e),
+                None => break,
+            }
+            next += 1;
+        }
+    }
+    None
+}
+
+/// Probes every item in `candidates` concurrently using up to `concurrency` worker threads and
+/// returns whether each one validated successfully, in the same order as `candidates`.
+///
+/// Unlike `probe_concurrently`, this does not stop at the first success: it is used by `status`,
+/// which needs to report on every candidate rather than just the one that would be selected.
+fn probe_all_concurrently(
+    candidates: Vec<PathBuf>,
+    concurrency: usize,
+    probe: impl Fn(&Path) -> Result<UnixStream> + Send + Sync + 'static,
+) -> Vec<bool> {
+    let len = candidates.len();
+    let rx = spawn_probe_pool(candidates, concurrency, probe);
+
+    let mut alive = vec![false; len];
+    for (i, outcome) in rx {
+        alive[i] = outcome.is_ok();
+    }
+    alive
+}
+
 /// Scans the contents of `dir`, which should point to a session directory created by sshd, looks
 /// for a valid socket, opens it, and returns the connection to the agent.
 ///
 /// This tries all possible files in search for a socket and only returns an error if no valid
-/// and alive candidate can be found.
-fn find_in_subdir(dir: &Path) -> Option<UnixStream> {
+/// and alive candidate can be found.  Candidates are probed concurrently, up to `concurrency`
+/// workers at a time.
+fn find_in_subdir(
+    dir: &Path,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Option<UnixStream> {
     let entries = match fs::read_dir(dir) {
         Ok(entries) => entries,
         Err(e) => {
@@ -102,30 +414,74 @@ fn find_in_subdir(dir: &Path) -> Option<UnixStream> {
         candidates.push(candidate);
     }
 
-    // The sorting is unnecessary but it helps with testing certain conditions.
+    // The sorting is unnecessary but it helps with testing certain conditions, and it gives us a
+    // deterministic tie-break when several candidates validate concurrently.
     candidates.sort();
 
-    for candidate in candidates {
-        let socket = match try_open(&candidate) {
-            Ok(socket) => socket,
+    let socket = probe_concurrently(
+        candidates,
+        concurrency,
+        move |candidate| try_open(candidate, probe_timeout),
+        |candidate, e| {
+            event::log_rejected(
+                &format!("Ignoring candidate socket {}: {}", candidate.display(), e),
+                candidate,
+                &e.to_string(),
+            )
+        },
+    );
+
+    match &socket {
+        Some(_) => {
+            event::log_selected(&format!("Successfully opened a socket in {}", dir.display()), dir)
+        }
+        None => event::log_no_socket(&format!("No socket in directory {}", dir.display()), dir),
+    }
+    socket
+}
+
+/// Like `find_in_subdir`, but reports on every candidate socket found in `dir` instead of
+/// stopping at the first one that answers.  Used by `status` to show the full picture.
+fn list_in_subdir(dir: &Path, probe_timeout: Option<Duration>, concurrency: usize) -> Vec<(PathBuf, bool)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to read directory entries in {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+
+    let mut candidates = vec![];
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
             Err(e) => {
-                trace!("Ignoring candidate socket {}: {}", candidate.display(), e);
+                debug!("Failed to read directory entry in {}: {}", dir.display(), e);
                 continue;
             }
         };
 
-        info!("Successfully opened socket at {}", candidate.display());
-        return Some(socket);
+        candidates.push(entry.path());
     }
+    candidates.sort();
 
-    debug!("No socket in directory {}", dir.display());
-    None
+    let order = candidates.clone();
+    let alive = probe_all_concurrently(candidates, concurrency, move |candidate| {
+        try_open(candidate, probe_timeout)
+    });
+
+    order.into_iter().zip(alive).collect()
 }
 
 /// Scans the contents of `dir`, which should point to one of the directories where sshd places the
 /// session directories for forwarded agents, looks for a valid connection to an agent, opens the
 /// agent's socket, and returns the connection to the agent.
-fn try_shared_subdir(dir: &Path, uid: libc::uid_t) -> Result<UnixStream> {
+///
+/// This is only ever called from inside one of `find_in_shared_dir`'s own pooled workers, so it
+/// scans `dir`'s candidates serially (`find_in_subdir` with a concurrency of 1) instead of spawning
+/// another full pool of its own: nesting two `concurrency`-sized pools would multiply the thread
+/// count by `concurrency` instead of merely adding to it.
+fn try_shared_subdir(dir: &Path, uid: libc::uid_t, probe_timeout: Option<Duration>) -> Result<UnixStream> {
     // It is tempting to use the *at family of system calls to avoid races when checking for
     // file metadata before opening the socket... but there is no guarantee that the sshd
     // instance will be present at all even after we open the socket, so the races don't
@@ -155,7 +511,7 @@ fn try_shared_subdir(dir: &Path, uid: libc::uid_t) -> Result<UnixStream> {
         ));
     }
 
-    match find_in_subdir(dir) {
+    match find_in_subdir(dir, probe_timeout, 1) {
         Some(socket) => Ok(socket),
         None => return Err(error!(ErrorKind::NotFound, "No socket in subdirectory")),
     }
@@ -167,7 +523,12 @@ fn try_shared_subdir(dir: &Path, uid: libc::uid_t) -> Result<UnixStream> {
 ///
 /// This tries all possible directories in search for a socket and only returns an error if no valid
 /// and alive candidate can be found.
-fn find_in_shared_dir(dir: &Path, our_uid: libc::uid_t) -> Option<UnixStream> {
+fn find_in_shared_dir(
+    dir: &Path,
+    our_uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Option<UnixStream> {
     let entries = match fs::read_dir(dir) {
         Ok(entries) => entries,
         Err(e) => {
@@ -190,11 +551,15 @@ fn find_in_shared_dir(dir: &Path, our_uid: libc::uid_t) -> Option<UnixStream> {
         match entry.file_type() {
             Ok(file_type) if file_type.is_dir() => (),
             Ok(_file_type) => {
-                trace!("Ignoring {}: not a directory", path.display());
+                event::log_rejected(
+                    &format!("Ignoring {}: not a directory", path.display()),
+                    &path,
+                    "not a directory",
+                );
                 continue;
             }
             Err(e) => {
-                trace!("Ignoring {}: {}", path.display(), e);
+                event::log_rejected(&format!("Ignoring {}: {}", path.display(), e), &path, &e.to_string());
                 continue;
             }
         };
@@ -205,20 +570,97 @@ fn find_in_shared_dir(dir: &Path, our_uid: libc::uid_t) -> Option<UnixStream> {
     // The sorting is unnecessary but it helps with testing certain conditions.
     subdirs.sort();
 
-    for subdir in subdirs {
-        let socket = match try_shared_subdir(&subdir, our_uid) {
-            Ok(socket) => socket,
+    let socket = probe_concurrently(
+        subdirs,
+        concurrency,
+        move |subdir| try_shared_subdir(subdir, our_uid, probe_timeout),
+        |subdir, e| event::log_rejected(&format!("Ignoring {}: {}", subdir.display(), e), subdir, &e.to_string()),
+    );
+
+    if socket.is_none() {
+        event::log_no_socket(&format!("No socket in directory: {}", dir.display()), dir);
+    }
+    socket
+}
+
+/// Validates that `dir` looks like an sshd-created session subdirectory owned by `uid`, same as
+/// `try_shared_subdir`, then lists every candidate socket within it instead of opening just one.
+/// Returns an empty vector if `dir` does not look like a valid subdirectory at all.
+fn list_shared_subdir(
+    dir: &Path,
+    uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<(PathBuf, bool)> {
+    let name = match dir.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return vec![],
+    };
+
+    if !name.starts_with("ssh-") {
+        return vec![];
+    }
+
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            trace!("Ignoring {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+
+    if metadata.uid() != uid {
+        trace!(
+            "Ignoring {}: owned by {}, not the current user {}",
+            dir.display(),
+            metadata.uid(),
+            uid
+        );
+        return vec![];
+    }
+
+    list_in_subdir(dir, probe_timeout, concurrency)
+}
+
+/// Like `find_in_shared_dir`, but reports on every candidate socket found across all of `dir`'s
+/// session subdirectories instead of stopping at the first one that answers.
+fn list_in_shared_dir(
+    dir: &Path,
+    our_uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<(PathBuf, bool)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to read directory entries in {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+
+    let mut subdirs = vec![];
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
             Err(e) => {
-                trace!("Ignoring {}: {}", subdir.display(), e);
+                debug!("Failed to read directory entry in {}: {}", dir.display(), e);
                 continue;
             }
         };
+        let path = entry.path();
 
-        return Some(socket);
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => subdirs.push(path),
+            Ok(_file_type) => trace!("Ignoring {}: not a directory", path.display()),
+            Err(e) => trace!("Ignoring {}: {}", path.display(), e),
+        };
     }
+    subdirs.sort();
 
-    debug!("No socket in directory: {}", dir.display());
-    None
+    subdirs
+        .into_iter()
+        .flat_map(|subdir| list_shared_subdir(&subdir, our_uid, probe_timeout, concurrency))
+        .collect()
 }
 
 /// Scans the contents of `dirs`, which should point to one or more session directories created
@@ -230,22 +672,49 @@ pub(super) fn find_socket(
     dirs: &[PathBuf],
     home: Option<&Path>,
     uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
 ) -> Option<UnixStream> {
     for dir in dirs {
         if let Some(home) = home {
             if dir.starts_with(home) {
                 debug!("Looking for an agent socket in {} with HOME naming scheme", dir.display());
-                if let Some(socket) = find_in_subdir(dir) {
+                if let Some(socket) = find_in_subdir(dir, probe_timeout, concurrency) {
                     return Some(socket);
                 }
             }
         }
 
         debug!("Looking for an agent socket in {} subdirs", dir.display());
-        if let Some(socket) = find_in_shared_dir(dir, uid) {
+        if let Some(socket) = find_in_shared_dir(dir, uid, probe_timeout, concurrency) {
             return Some(socket);
         }
     }
 
     None
 }
+
+/// Scans `dirs` the same way `find_socket` does, but reports on every candidate socket found
+/// instead of stopping at the first one that answers a liveness probe.  This is the backing logic
+/// for the `status` subcommand, which wants the full picture rather than just a winner.
+pub(super) fn list_candidates(
+    dirs: &[PathBuf],
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    probe_timeout: Option<Duration>,
+    concurrency: usize,
+) -> Vec<(PathBuf, bool)> {
+    let mut candidates = vec![];
+
+    for dir in dirs {
+        if let Some(home) = home {
+            if dir.starts_with(home) {
+                candidates.extend(list_in_subdir(dir, probe_timeout, concurrency));
+            }
+        }
+
+        candidates.extend(list_in_shared_dir(dir, uid, probe_timeout, concurrency));
+    }
+
+    candidates
+}
@@ -24,22 +24,159 @@
 //! Serves a Unix domain socket that proxies connections to any valid SSH agent provided by sshd.
 
 use log::{debug, info, warn};
-use signal_hook::{consts::SIGHUP, consts::TERM_SIGNALS, iterator::Signals};
-use std::io;
+use signal_hook::iterator::exfiltrator::origin::WithOrigin;
+use signal_hook::iterator::SignalsInfo;
+use signal_hook::{consts::SIGHUP, consts::TERM_SIGNALS, low_level};
+use std::fmt;
+use std::io::{self, Read};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
+mod aggregate;
+mod control;
+pub mod event;
+pub mod fallback;
+mod failover;
+mod fdpass;
 mod find;
+pub mod policy;
 mod proxy;
+mod proxy_protocol;
+pub mod tcp;
 
 /// Result type for this crate.
 type Result<T> = std::result::Result<T, String>;
 
+/// Default timeout to wait for a candidate agent socket to answer a liveness probe.
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Default number of worker threads used to probe candidates in a directory concurrently.
+pub const DEFAULT_SCAN_CONCURRENCY: usize = 8;
+
+/// Default number of worker threads used to handle accepted client connections concurrently.
+pub const DEFAULT_CONNECTION_CONCURRENCY: usize = 8;
+
+/// Default read timeout used by `--failover` to detect a stalled backend between requests.
+pub const DEFAULT_FAILOVER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One connection accepted off any listener, dispatched to a worker thread in `run`'s pool.
+enum Job {
+    Unix(UnixStream),
+    Tcp(std::net::TcpStream),
+    Control(UnixStream),
+}
+
+/// The subset of `run`'s configuration that can change at runtime, via `SIGHUP`, without dropping
+/// the listening socket or disrupting connections already being proxied.
+///
+/// `run` keeps one of these behind an `Arc<RwLock<Config>>`; each connection handler takes a
+/// snapshot of it at the start of the connection, so a reload never affects a connection already in
+/// progress, only ones accepted afterwards.
+#[derive(Clone)]
+pub struct Config {
+    /// Directories to look for running agents in.
+    pub agents_dirs: Vec<PathBuf>,
+
+    /// How long to wait for a candidate agent socket to answer a liveness probe before rejecting
+    /// it; `None` disables probing and falls back to connect-only validation.
+    pub probe_timeout: Option<Duration>,
+
+    /// Maximum number of candidate sockets to probe at once within a directory.
+    pub scan_concurrency: usize,
+
+    /// Restricts which requests are forwarded to the real agent; see `policy::Mode`.
+    pub policy: Option<policy::Mode>,
+
+    /// Whether to expose the union of every live backend's identities instead of picking just one;
+    /// see `aggregate::proxy_aggregated`.  Mutually exclusive with `policy`, same as `--readonly` and
+    /// `--sign-only` are mutually exclusive with each other.
+    pub aggregate: bool,
+
+    /// Whether to transparently reselect and reconnect to another live backend if the active one
+    /// closes the connection or errors out mid-connection, instead of letting the client's
+    /// connection die with it; see `failover::proxy_with_failover`.  Mutually exclusive with
+    /// `policy` and `aggregate`.
+    pub failover: bool,
+
+    /// How long to wait for the active backend to answer a request before considering it stalled;
+    /// a stalled backend ends the connection with an error instead of triggering a failover, since
+    /// the request has already been delivered to it.  Only meaningful when `failover` is set.
+    pub failover_read_timeout: Duration,
+
+    /// How long a proxied connection -- handled by `proxy::proxy_request`, i.e. neither
+    /// `--readonly`/`--sign-only`, `--aggregate`, nor `--failover` -- may sit with neither side
+    /// making progress before it is aborted; `None` (the default -- this is opt-in via
+    /// `--idle-timeout`) leaves connections unbounded.  Protects against accumulating half-open
+    /// connections left behind by agent forwarding sessions whose SSH connection died without
+    /// closing the local socket.
+    pub idle_timeout: Option<Duration>,
+
+    /// How long a proxied connection may stay open in total, regardless of activity; `None` (the
+    /// default -- this is opt-in via `--total-timeout`) leaves connections unbounded.  Unlike
+    /// `idle_timeout`, this cap also applies to a connection that is actively and legitimately in
+    /// use the whole time, so it is off unless explicitly requested.
+    pub total_timeout: Option<Duration>,
+
+    /// Whether `--listen`/`--listen-tcp` connections are expected to start with a PROXY protocol v2
+    /// header (e.g. because they arrive via socat, haproxy, or `ssh -L` rather than directly from
+    /// the real client) that should be parsed and stripped before any agent message is read; see
+    /// `proxy_protocol::read_header`. Has no effect on the primary Unix socket, which never sees a
+    /// TCP forwarder in front of it.
+    pub proxy_protocol: bool,
+}
+
+/// Broad category of a failure out of `run`, used to pick a meaningful process exit status.
+///
+/// Distinguishing these lets a supervisor or a shell wrapper react differently to, say, a
+/// transient "no agent directory is readable yet" condition versus a hard misconfiguration that
+/// will never clear up on its own.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// An unexpected internal error (e.g. a thread panicked or a signal handler failed to set up).
+    Internal,
+
+    /// Could not bind or listen on the requested `--socket-path`.
+    Socket,
+
+    /// None of the configured agent directories could be read.
+    NoAgentDir,
+}
+
+/// An error out of `run`, tagged with a category so that callers can map it to a specific process
+/// exit status.
+#[derive(Debug)]
+pub struct Error {
+    category: ErrorCategory,
+    message: String,
+}
+
+impl Error {
+    /// Constructs a new error with the given `category` and `message`.
+    fn new(category: ErrorCategory, message: impl Into<String>) -> Error {
+        Error { category, message: message.into() }
+    }
+
+    /// Returns the category of this error.
+    pub fn category(&self) -> ErrorCategory {
+        self.category
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
 /// A scope guard to restore the previous umask.
 struct UmaskGuard {
     old_umask: libc::mode_t,
@@ -56,25 +193,55 @@ fn set_umask(umask: libc::mode_t) -> UmaskGuard {
     UmaskGuard { old_umask: unsafe { libc::umask(umask) } }
 }
 
-/// Installs global signal handlers for termination signals.
+/// Installs global signal handlers for `SIGHUP` and termination signals.
+///
+/// `SIGHUP` triggers a configuration reload: `reload` is called to compute a fresh `Config`, which
+/// replaces the contents of `config` for subsequent connections without affecting ones already in
+/// progress or touching the listening socket.
 ///
-/// Returns a thread that blocks until any of the signals is received and immediately deletes
+/// Returns a thread that blocks until a termination signal is received and immediately deletes
 /// `cleanup_files` before returning.
-fn setup_signals(cleanup_files: &[&Path], stop: Arc<AtomicBool>) -> Result<JoinHandle<()>> {
+fn setup_signals(
+    cleanup_files: &[&Path],
+    stop: Arc<AtomicBool>,
+    config: Arc<RwLock<Config>>,
+    reload: Arc<dyn Fn() -> Result<Config> + Send + Sync>,
+) -> Result<JoinHandle<()>> {
     let mut sigs = vec![SIGHUP];
     sigs.extend(TERM_SIGNALS);
-    let mut signals = Signals::new(&sigs)
+    let mut signals = SignalsInfo::<WithOrigin>::new(&sigs)
         .map_err(|e| format!("Cannot set up termination signal handlers: {}", e))?;
 
     let handle = {
         let cleanup_files =
             cleanup_files.into_iter().map(|p| (*p).to_owned()).collect::<Vec<PathBuf>>();
         thread::spawn(move || {
-            for sig in signals.forever() {
+            for info in signals.forever() {
+                let sig = info.signal;
+                // Who sent the signal, for auditing who stopped or reloaded the agent proxy; not
+                // always available (e.g. a signal raised by the kernel itself has no sender).
+                let origin = match info.process {
+                    Some(process) => format!(" (sent by pid {} uid {})", process.pid, process.uid),
+                    None => String::new(),
+                };
+
+                if sig == SIGHUP {
+                    info!("Reloading configuration due to SIGHUP{}", origin);
+                    match reload() {
+                        Ok(new_config) => {
+                            *config.write().unwrap() = new_config;
+                            info!("Configuration reloaded");
+                        }
+                        Err(e) => warn!("Failed to reload configuration, keeping the old one: {}", e),
+                    }
+                    continue;
+                }
+
                 if TERM_SIGNALS.contains(&sig) {
                     info!(
-                        "Shutting down due to signal {:?} and removing {}",
+                        "Shutting down due to signal {:?}{} and removing {}",
                         sig,
+                        origin,
                         cleanup_files
                             .iter()
                             .map(|p| (*p).display().to_string())
@@ -83,7 +250,8 @@ fn setup_signals(cleanup_files: &[&Path], stop: Arc<AtomicBool>) -> Result<JoinH
                     );
                     break;
                 }
-                debug!("Ignoring signal {:?}", sig);
+
+                debug!("Ignoring signal {:?}{}", sig, origin);
             }
 
             for file in cleanup_files {
@@ -97,6 +265,39 @@ fn setup_signals(cleanup_files: &[&Path], stop: Arc<AtomicBool>) -> Result<JoinH
     Ok(handle)
 }
 
+/// Where the primary agent-proxy Unix socket is bound.
+///
+/// A TCP endpoint is deliberately not a variant here: exposing the primary listener over the
+/// network reuses the existing, token-gated `tcp` machinery that `--listen-tcp` already provides
+/// (see `run`'s `socket_path`/`tcp` parameters) rather than adding a second, differently-secured
+/// way to accept TCP connections.
+#[derive(Clone, Debug)]
+pub enum SocketSpec {
+    /// A filesystem path, bound with `UnixListener::bind` and deleted on exit (unless inherited
+    /// from a supervisor; see `inherited_listener`).
+    Path(PathBuf),
+
+    /// A Linux abstract socket name, written by the user as `--socket-path @name`.  Abstract
+    /// sockets have no filesystem entry, so there is nothing to delete on exit and no permissions
+    /// to restrict: visibility is already scoped to the current network namespace.
+    Abstract(String),
+}
+
+impl fmt::Display for SocketSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketSpec::Path(path) => write!(f, "{}", path.display()),
+            SocketSpec::Abstract(name) => write!(f, "@{}", escape_abstract_name(name)),
+        }
+    }
+}
+
+/// Escapes `name` for logging/error messages using the same convention `std::ascii::escape_default`
+/// applies to single bytes, so that a name containing control characters doesn't corrupt log output.
+fn escape_abstract_name(name: &str) -> String {
+    name.bytes().flat_map(std::ascii::escape_default).map(|b| b as char).collect()
+}
+
 /// Creates the agent socket to listen on.
 ///
 /// This makes sure that the socket is only accessible by the current user.
@@ -109,74 +310,791 @@ fn create_listener(socket_path: &Path) -> Result<UnixListener> {
         .map_err(|e| format!("Cannot listen on {}: {}", socket_path.display(), e))
 }
 
-/// Handles one incoming connection on `client`.
+/// Creates the primary agent socket described by `spec`, dispatching on its kind.
+fn create_primary_listener(spec: &SocketSpec) -> Result<UnixListener> {
+    match spec {
+        SocketSpec::Path(path) => create_listener(path),
+        SocketSpec::Abstract(name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                .map_err(|e| format!("Invalid abstract socket name '{}': {}", escape_abstract_name(name), e))?;
+            UnixListener::bind_addr(&addr).map_err(|e| {
+                format!("Cannot listen on abstract socket '{}': {}", escape_abstract_name(name), e)
+            })
+        }
+    }
+}
+
+/// Environment variable a supervisor sets to the PID of the process meant to receive the listening
+/// sockets it is passing down; see sd_listen_fds(3).
+const LISTEN_PID_VAR: &str = "LISTEN_PID";
+
+/// Environment variable a supervisor sets to the number of sockets it is passing down, starting at
+/// file descriptor 3; see sd_listen_fds(3).
+const LISTEN_FDS_VAR: &str = "LISTEN_FDS";
+
+/// First inherited file descriptor under the sd_listen_fds(3) convention, which systemd's socket
+/// activation and similar supervisors (e.g. einhorn) follow.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Adopts a listener socket pre-bound by a supervisor, if one was handed down to us.
+///
+/// This follows the sd_listen_fds(3) convention: `LISTEN_PID` must name our own PID (so that a
+/// child process that merely inherited the supervisor's environment doesn't also try to adopt the
+/// socket) and `LISTEN_FDS` must be at least 1, in which case the first passed descriptor (fd 3) is
+/// adopted. Returns `None` -- meaning `run` should bind its own socket at `socket_path` instead --
+/// if either variable is absent or doesn't apply to us.
+///
+/// Unlike a socket we bind ourselves, an inherited socket's file lives and dies with the
+/// supervisor, not with us, so `run` must not delete `socket_path` on exit in this case.
+fn inherited_listener() -> Option<UnixListener> {
+    let listen_pid: libc::pid_t = env::var(LISTEN_PID_VAR).ok()?.parse().ok()?;
+    if listen_pid != unsafe { libc::getpid() } {
+        return None;
+    }
+
+    let listen_fds: u32 = env::var(LISTEN_FDS_VAR).ok()?.parse().ok()?;
+    if listen_fds < 1 {
+        return None;
+    }
+
+    // Safety: the supervisor guarantees that fd LISTEN_FDS_START is open, already bound and
+    // listening, and ours to own from here on; that's the sd_listen_fds(3) contract we rely on.
+    Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Runs the agent-selection logic against `agents_dirs` without binding a listening socket, and
+/// returns the path of the socket that would be used to proxy requests, if any.
+///
+/// This exists to support `--check`, a dry-run mode for diagnosing why forwarding isn't working.
+/// Raise the log level to `trace` to see why each rejected candidate was turned down.
+pub fn check(
+    agents_dirs: &[PathBuf],
+    probe_timeout: Option<Duration>,
+    scan_concurrency: usize,
+) -> Option<PathBuf> {
+    let home = env::var("HOME").map(|v| Some(PathBuf::from(v))).unwrap_or(None);
+    let uid = unsafe { libc::getuid() };
+
+    let socket = find::find_socket(agents_dirs, home.as_deref(), uid, probe_timeout, scan_concurrency)?;
+    socket.peer_addr().ok()?.as_pathname().map(|p| p.to_owned())
+}
+
+/// Liveness of one candidate agent socket discovered while computing a `Status`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CandidateStatus {
+    /// Path to the candidate socket.
+    pub path: PathBuf,
+
+    /// Whether the candidate answered a `REQUEST_IDENTITIES` liveness probe.
+    pub alive: bool,
+}
+
+/// The full picture of agent discovery: every candidate socket found, whether each one is alive,
+/// and which one (if any) `run` would currently select to proxy requests to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Status {
+    /// Every candidate socket found across the configured agent directories.
+    pub candidates: Vec<CandidateStatus>,
+
+    /// The candidate `run` would select: the first alive one, in the same order `find_socket`
+    /// would consider them.
+    pub selected: Option<PathBuf>,
+}
+
+/// Runs the same discovery `check` does, but reports on every candidate socket found instead of
+/// stopping at the first one that answers a liveness probe.
+///
+/// This exists to support the `status` subcommand, which needs to show the full picture -- not
+/// just the winner -- so that an operator can tell a hung agent apart from one that was never
+/// found at all.
+pub fn status(
+    agents_dirs: &[PathBuf],
+    probe_timeout: Option<Duration>,
+    scan_concurrency: usize,
+) -> Status {
+    let home = env::var("HOME").map(|v| Some(PathBuf::from(v))).unwrap_or(None);
+    let uid = unsafe { libc::getuid() };
+
+    let candidates = find::list_candidates(agents_dirs, home.as_deref(), uid, probe_timeout, scan_concurrency);
+    let selected = candidates.iter().find(|(_path, alive)| *alive).map(|(path, _alive)| path.clone());
+
+    Status {
+        candidates: candidates.into_iter().map(|(path, alive)| CandidateStatus { path, alive }).collect(),
+        selected,
+    }
+}
+
+/// Handles one incoming connection on `client`, using the configuration snapshot in `config`.
 fn handle_connection(
     mut client: UnixStream,
-    agents_dirs: &[PathBuf],
+    config: &Config,
     home: Option<&Path>,
     uid: libc::uid_t,
+    fallback_agent: Option<&fallback::LazyFallback>,
+    control_state: &control::ControlState,
+    stop: &AtomicBool,
 ) -> Result<()> {
-    let mut agent = match find::find_socket(agents_dirs, home, uid) {
+    let _guard = control_state.connection_started();
+
+    if config.aggregate {
+        return handle_aggregate_connection(client, config, home, uid, control_state, stop);
+    }
+
+    let mut agent = match find::find_socket(
+        &config.agents_dirs,
+        home,
+        uid,
+        config.probe_timeout,
+        config.scan_concurrency,
+    ) {
         Some(socket) => socket,
-        None => {
-            return Err("No agent found; cannot proxy request".to_owned());
+        None => match fallback_agent {
+            Some(fallback) => fallback
+                .connect()
+                .map_err(|e| format!("No forwarded agent found and fallback failed: {}", e))?,
+            None => {
+                return Err("No agent found; cannot proxy request".to_owned());
+            }
+        },
+    };
+    let selected_socket =
+        agent.peer_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_owned()));
+    control_state.record_selected(selected_socket.as_deref());
+    let client_pid = peer_pid(&client);
+    event::log_connection_served(selected_socket.as_deref(), client_pid);
+
+    let start = Instant::now();
+    let (result, bytes, failovers) = if config.failover {
+        let reselect = || {
+            find::find_socket(&config.agents_dirs, home, uid, config.probe_timeout, config.scan_concurrency)
+        };
+        split_failover(failover::proxy_with_failover(
+            &mut client,
+            agent,
+            config.failover_read_timeout,
+            reselect,
+            stop,
+        ))
+    } else {
+        match config.policy {
+            Some(mode) => {
+                let (result, bytes) =
+                    split_bytes(policy::proxy_filtered(&mut client, &mut agent, mode, stop));
+                (result, bytes, 0)
+            }
+            // The fd-passing fast path hands the client the real agent socket outright, so it
+            // must never be offered when a policy is filtering requests: skip straight to the
+            // byte proxy.
+            None => match fdpass::try_offer(&mut client, &agent, stop) {
+                Ok(true) => {
+                    debug!("Handed off agent socket to client via SCM_RIGHTS");
+                    (Ok(()), None, 0)
+                }
+                Ok(false) => {
+                    let (result, bytes) = split_bytes(
+                        proxy::proxy_request(
+                            &mut client,
+                            &mut agent,
+                            config.idle_timeout,
+                            config.total_timeout,
+                            stop,
+                        )
+                        .map(|stats| (stats.bytes_to_agent, stats.bytes_to_client)),
+                    );
+                    (result, bytes, 0)
+                }
+                Err(e) => (Err(e), None, 0),
+            },
         }
     };
-    let result = proxy::proxy_request(&mut client, &mut agent).map_err(|e| format!("{}", e));
+    event::log_connection_closed(&event::ConnectionSummary {
+        selected_socket: selected_socket.as_deref(),
+        client_pid,
+        client_addr: None,
+        bytes,
+        failovers,
+        duration: start.elapsed(),
+        error: result.as_ref().err().map(String::as_str),
+    });
     debug!("Closing client connection");
     result
 }
 
+/// Handles one `--aggregate` connection on `client`: connects to every live backend in
+/// `config.agents_dirs` and exposes their merged identities as a single virtual agent; see
+/// `aggregate::proxy_aggregated`.
+///
+/// Unlike `handle_connection`'s normal path, there is no single `selected_socket` to report -- the
+/// whole point of this mode is that more than one backend may serve the connection -- so the
+/// `event::ConnectionSummary` for an aggregated connection always reports `None` for both.
+fn handle_aggregate_connection(
+    mut client: UnixStream,
+    config: &Config,
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    control_state: &control::ControlState,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut agents = find::connect_all_live(
+        &config.agents_dirs,
+        home,
+        uid,
+        config.probe_timeout,
+        config.scan_concurrency,
+    );
+    if agents.is_empty() {
+        return Err("No agent found; cannot proxy request".to_owned());
+    }
+    control_state.record_selected(None);
+    let client_pid = peer_pid(&client);
+    event::log_connection_served(None, client_pid);
+
+    let start = Instant::now();
+    let result = aggregate::proxy_aggregated(&mut client, &mut agents, stop);
+    event::log_connection_closed(&event::ConnectionSummary {
+        selected_socket: None,
+        client_pid,
+        client_addr: None,
+        bytes: None,
+        failovers: 0,
+        duration: start.elapsed(),
+        error: result.as_ref().err().map(String::as_str),
+    });
+    debug!("Closing aggregated client connection");
+    result
+}
+
+/// Splits a proxying result carrying byte counts into a plain `Result<()>` and the counts on
+/// success, so that both `handle_connection` and `handle_tcp_connection` can feed the same shape
+/// into `event::ConnectionSummary` regardless of which proxying path was taken.
+fn split_bytes(result: Result<(u64, u64)>) -> (Result<()>, Option<(u64, u64)>) {
+    match result {
+        Ok(counts) => (Ok(()), Some(counts)),
+        Err(e) => (Err(e), None),
+    }
+}
+
+/// Splits a `failover::proxy_with_failover` result into the same `(Result<()>, Option<(u64, u64)>)`
+/// shape `split_bytes` produces, plus the number of failovers that occurred, for
+/// `event::ConnectionSummary`.
+fn split_failover(
+    result: Result<failover::FailoverResult>,
+) -> (Result<()>, Option<(u64, u64)>, usize) {
+    match result {
+        Ok(stats) => {
+            (Ok(()), Some((stats.bytes_to_agent, stats.bytes_to_client)), stats.failovers)
+        }
+        Err(e) => (Err(e), None, 0),
+    }
+}
+
+/// Returns the PID of the process on the other end of `stream`, if the platform and kernel support
+/// retrieving it; `None` on any failure, which simply means the `event::log_connection_served`
+/// record will omit it.
+fn peer_pid(stream: &UnixStream) -> Option<libc::pid_t> {
+    let mut ucred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc == 0 {
+        Some(ucred.pid)
+    } else {
+        None
+    }
+}
+
+/// Handles one incoming connection on `client`, authenticating it against `token` first and using
+/// the configuration snapshot in `config`.
+fn handle_tcp_connection(
+    mut client: std::net::TcpStream,
+    token: &[u8],
+    config: &Config,
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    fallback_agent: Option<&fallback::LazyFallback>,
+    control_state: &control::ControlState,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let proxy_protocol_addr = if config.proxy_protocol {
+        proxy_protocol::read_header(&mut client)?.source_addr
+    } else {
+        None
+    };
+
+    match tcp::authenticate(&mut client, token) {
+        Ok(true) => (),
+        Ok(false) => return Err("Client presented an invalid token".to_owned()),
+        Err(e) => return Err(format!("Failed to authenticate client: {}", e)),
+    }
+    // Prefer the address the forwarder reported as the real client's over the TCP peer address,
+    // which under `--proxy-protocol` is just the forwarder itself.
+    let client_addr = proxy_protocol_addr.or_else(|| client.peer_addr().ok().map(|a| a.to_string()));
+
+    let _guard = control_state.connection_started();
+
+    if config.aggregate {
+        return handle_aggregate_tcp_connection(
+            client,
+            client_addr,
+            config,
+            home,
+            uid,
+            control_state,
+            stop,
+        );
+    }
+
+    let mut agent = match find::find_socket(
+        &config.agents_dirs,
+        home,
+        uid,
+        config.probe_timeout,
+        config.scan_concurrency,
+    ) {
+        Some(socket) => socket,
+        None => match fallback_agent {
+            Some(fallback) => fallback
+                .connect()
+                .map_err(|e| format!("No forwarded agent found and fallback failed: {}", e))?,
+            None => {
+                return Err("No agent found; cannot proxy request".to_owned());
+            }
+        },
+    };
+    let selected_socket =
+        agent.peer_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_owned()));
+    control_state.record_selected(selected_socket.as_deref());
+    event::log_connection_served(selected_socket.as_deref(), None);
+
+    let start = Instant::now();
+    let (result, bytes, failovers) = if config.failover {
+        let reselect = || {
+            find::find_socket(&config.agents_dirs, home, uid, config.probe_timeout, config.scan_concurrency)
+        };
+        split_failover(failover::proxy_with_failover(
+            &mut client,
+            agent,
+            config.failover_read_timeout,
+            reselect,
+            stop,
+        ))
+    } else {
+        match config.policy {
+            Some(mode) => {
+                let (result, bytes) =
+                    split_bytes(policy::proxy_filtered(&mut client, &mut agent, mode, stop));
+                (result, bytes, 0)
+            }
+            None => {
+                let (result, bytes) = split_bytes(
+                    proxy::proxy_request(
+                        &mut client,
+                        &mut agent,
+                        config.idle_timeout,
+                        config.total_timeout,
+                        stop,
+                    )
+                    .map(|stats| (stats.bytes_to_agent, stats.bytes_to_client)),
+                );
+                (result, bytes, 0)
+            }
+        }
+    };
+    event::log_connection_closed(&event::ConnectionSummary {
+        selected_socket: selected_socket.as_deref(),
+        client_pid: None,
+        client_addr: client_addr.as_deref(),
+        bytes,
+        failovers,
+        duration: start.elapsed(),
+        error: result.as_ref().err().map(String::as_str),
+    });
+    debug!("Closing TCP client connection");
+    result
+}
+
+/// The TCP counterpart of `handle_aggregate_connection`; see there.
+fn handle_aggregate_tcp_connection(
+    mut client: std::net::TcpStream,
+    client_addr: Option<String>,
+    config: &Config,
+    home: Option<&Path>,
+    uid: libc::uid_t,
+    control_state: &control::ControlState,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut agents = find::connect_all_live(
+        &config.agents_dirs,
+        home,
+        uid,
+        config.probe_timeout,
+        config.scan_concurrency,
+    );
+    if agents.is_empty() {
+        return Err("No agent found; cannot proxy request".to_owned());
+    }
+    control_state.record_selected(None);
+    event::log_connection_served(None, None);
+
+    let start = Instant::now();
+    let result = aggregate::proxy_aggregated(&mut client, &mut agents, stop);
+    event::log_connection_closed(&event::ConnectionSummary {
+        selected_socket: None,
+        client_pid: None,
+        client_addr: client_addr.as_deref(),
+        bytes: None,
+        failovers: 0,
+        duration: start.elapsed(),
+        error: result.as_ref().err().map(String::as_str),
+    });
+    debug!("Closing aggregated TCP client connection");
+    result
+}
+
 /// Runs the core logic of the app.
 ///
-/// This serves the SSH agent socket on `socket_path` and looks for sshd sockets in `agents_dirs`.
+/// This serves the SSH agent socket on `socket_path` and looks for sshd sockets in the directories
+/// named by `initial_config.agents_dirs`.
+///
+/// The `pid_file` needs to be passed in for cleanup purposes.  `fallback_agent`, when set, is used
+/// whenever no forwarded agent can be found.  `tcp`, when set, is an already-bound TCP listener (see
+/// `tcp::bind`) that is served alongside the Unix socket, gated behind its token.  `log_format`
+/// selects between free-form and JSON-structured logging for discovery and connection events; see
+/// `event::LogFormat`.  `connection_concurrency` bounds how many accepted connections are handled at
+/// once; connections beyond that are queued until a worker becomes free, so a slow or stalled agent
+/// request no longer blocks every other client the way handling connections inline used to.
 ///
-/// The `pid_file` needs to be passed in for cleanup purposes.
-pub fn run(socket_path: PathBuf, agents_dirs: &[PathBuf], pid_file: PathBuf) -> Result<()> {
+/// `initial_config` seeds the reloadable settings -- the agent directories, probe timeout, scan
+/// concurrency, and policy -- and `reload` is called both on `SIGHUP` and on a `control_socket`
+/// `reload` command to recompute them; the result replaces `initial_config`'s contents for
+/// connections accepted afterwards without affecting ones already in progress.
+///
+/// `control_socket`, when set, is served alongside the agent-proxy socket and answers `list`,
+/// `status`, and `reload` commands; see the `control` module.
+///
+/// `socket_path` is `None` when the primary listener is being served exclusively over `tcp`
+/// instead (the `--listen` flag), in which case no Unix socket is bound or cleaned up at all.
+pub fn run(
+    socket_path: Option<SocketSpec>,
+    initial_config: Config,
+    pid_file: PathBuf,
+    fallback_agent: Option<fallback::FallbackConfig>,
+    tcp: Option<tcp::BoundTcp>,
+    control_socket: Option<PathBuf>,
+    log_format: event::LogFormat,
+    connection_concurrency: usize,
+    reload: Arc<dyn Fn() -> Result<Config> + Send + Sync>,
+) -> std::result::Result<(), Error> {
+    event::set_format(log_format);
+
+    if !initial_config.agents_dirs.iter().any(|dir| fs::read_dir(dir).is_ok()) {
+        return Err(Error::new(
+            ErrorCategory::NoAgentDir,
+            format!(
+                "None of the configured agent directories could be read: {}",
+                initial_config
+                    .agents_dirs
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ));
+    }
+
+    let fallback_agent = fallback_agent.map(fallback::LazyFallback::new);
     let home = env::var("HOME").map(|v| Some(PathBuf::from(v))).unwrap_or(None);
     let uid = unsafe { libc::getuid() };
+    let config = Arc::new(RwLock::new(initial_config));
+
+    // If a supervisor handed us an already-bound listener (e.g. systemd socket activation), we
+    // must not delete socket_path on exit: the supervisor owns that file, not us.  There is
+    // nothing to inherit when the primary listener is TCP-only (`socket_path` is `None`).
+    let inherited_listener = if socket_path.is_some() { inherited_listener() } else { None };
 
     // Install signal handlers before we create the socket so that we don't leave it behind in any
     // case.
     let stop = Arc::from(AtomicBool::new(false));
-    let cleanup_files = [
-        socket_path.as_path(),
-        // Because we catch signals, daemonize doesn't properly clean up the PID file so we have.
-        // to do it ourselves.
-        pid_file.as_path(),
-    ];
-    let handle = setup_signals(cleanup_files.as_slice(), stop.clone())?;
-
-    let listener = create_listener(&socket_path)?;
-
-    // TODO(jmmv): signal_hook forcibly enables `SA_RESTART` so, for simplicity, we do active
-    // polling of the termination condition.  This is ugly though: we should use a pipe and select
-    // below.
-    listener
-        .set_nonblocking(true)
-        .map_err(|e| format!("Cannot set socket to non-blocking: {}", e))?;
+    let mut cleanup_files: Vec<&Path> = vec![pid_file.as_path()];
+    if inherited_listener.is_none() {
+        // Because we catch signals, daemonize doesn't properly clean up the PID file so we have to
+        // do it ourselves; an inherited listener's file lives and dies with the supervisor, and an
+        // abstract socket or a TCP-only primary has no filesystem entry to remove in the first
+        // place.
+        if let Some(SocketSpec::Path(path)) = &socket_path {
+            cleanup_files.push(path.as_path());
+        }
+    }
+    if let Some(path) = &control_socket {
+        cleanup_files.push(path.as_path());
+    }
+    let handle = setup_signals(
+        cleanup_files.as_slice(),
+        stop.clone(),
+        Arc::clone(&config),
+        Arc::clone(&reload),
+    )
+    .map_err(|e| Error::new(ErrorCategory::Internal, e))?;
+
+    let listener = match &socket_path {
+        Some(spec) => {
+            let listener = match inherited_listener {
+                Some(listener) => {
+                    debug!("Adopting inherited listener socket from supervisor");
+                    listener
+                }
+                None => {
+                    create_primary_listener(spec).map_err(|e| Error::new(ErrorCategory::Socket, e))?
+                }
+            };
+
+            // Kept non-blocking defensively: poll() below only calls accept() once the listener is
+            // reported readable, but this guarantees accept() itself can never block even in the
+            // unlikely case that readiness turned out to be stale by the time we get to it.
+            listener.set_nonblocking(true).map_err(|e| {
+                Error::new(ErrorCategory::Internal, format!("Cannot set socket to non-blocking: {}", e))
+            })?;
+            info!("Listening for agent connections on {}", spec);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let (tcp_listener, tcp_token) = match tcp {
+        Some(bound) => {
+            bound.listener.set_nonblocking(true).map_err(|e| {
+                Error::new(
+                    ErrorCategory::Internal,
+                    format!("Cannot set TCP socket to non-blocking: {}", e),
+                )
+            })?;
+            info!("Listening for TCP agent forwarding connections on port {}", bound.port);
+            (Some(bound.listener), bound.token)
+        }
+        None => (None, Vec::new()),
+    };
+
+    let control_listener = match &control_socket {
+        Some(path) => {
+            let listener =
+                create_listener(path).map_err(|e| Error::new(ErrorCategory::Socket, e))?;
+            listener.set_nonblocking(true).map_err(|e| {
+                Error::new(
+                    ErrorCategory::Internal,
+                    format!("Cannot set control socket to non-blocking: {}", e),
+                )
+            })?;
+            info!("Listening for control connections on {}", path.display());
+            Some(listener)
+        }
+        None => None,
+    };
+    let control_state = control::ControlState::new();
+
+    // Connections are dispatched to a bounded pool of worker threads instead of being handled
+    // inline, so a slow or stalled agent request doesn't stall unrelated clients.  The channel has
+    // no buffer of its own: `job_sender.send` blocks until a worker is free, which is what bounds
+    // how many connections are in flight at once.
+    let (job_sender, job_receiver) = mpsc::sync_channel::<Job>(0);
+    let job_receiver = Mutex::new(job_receiver);
+
+    // Self-pipe trick: a termination signal writes a byte to wake_write from inside the signal
+    // handler itself (signal-hook's low_level::pipe does this in an async-signal-safe way), which
+    // wakes poll() below immediately instead of leaving it to a periodic, up-to-100ms-late wakeup.
+    let (mut wake_read, wake_write) = UnixStream::pair().map_err(|e| {
+        Error::new(ErrorCategory::Internal, format!("Cannot create wakeup pipe: {}", e))
+    })?;
+    wake_read.set_nonblocking(true).map_err(|e| {
+        Error::new(ErrorCategory::Internal, format!("Cannot set wakeup pipe to non-blocking: {}", e))
+    })?;
+    let _wake_sig_ids = TERM_SIGNALS
+        .iter()
+        .map(|&sig| {
+            let wake_write = wake_write.try_clone().map_err(|e| {
+                Error::new(ErrorCategory::Internal, format!("Cannot clone wakeup pipe: {}", e))
+            })?;
+            low_level::pipe::register(sig, wake_write).map_err(|e| {
+                Error::new(
+                    ErrorCategory::Internal,
+                    format!("Cannot register wakeup pipe for signal {}: {}", sig, e),
+                )
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, Error>>()?;
 
     debug!("Entering main loop");
-    while !stop.load(Ordering::Relaxed) {
-        match listener.accept() {
-            Ok((socket, _addr)) => {
-                debug!("Connection accepted");
-                // TODO(jmmv): Connections are handled sequentially.  This is just fine for this
-                // program, but if we had an easier way to do asynchronous operations, we could
-                // fix this.
-                if let Err(e) = handle_connection(socket, agents_dirs, home.as_deref(), uid) {
-                    warn!("Dropping connection due to error: {}", e);
+    thread::scope(|scope| {
+        for _ in 0..connection_concurrency {
+            let job_receiver = &job_receiver;
+            let config = &config;
+            let control_state = &control_state;
+            let reload = &reload;
+            let stop = &stop;
+            scope.spawn(move || loop {
+                let job = match job_receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                match job {
+                    Job::Unix(socket) => {
+                        // Snapshot the config once per job, not once per worker: a SIGHUP or
+                        // control-socket reload must never affect a connection already being
+                        // handled, only ones dispatched afterwards.
+                        let config = config.read().unwrap().clone();
+                        if let Err(e) = handle_connection(
+                            socket,
+                            &config,
+                            home.as_deref(),
+                            uid,
+                            fallback_agent.as_ref(),
+                            control_state,
+                            stop,
+                        ) {
+                            warn!("Dropping connection due to error: {}", e);
+                        }
+                    }
+                    Job::Tcp(socket) => {
+                        let config = config.read().unwrap().clone();
+                        if let Err(e) = handle_tcp_connection(
+                            socket,
+                            &tcp_token,
+                            &config,
+                            home.as_deref(),
+                            uid,
+                            fallback_agent.as_ref(),
+                            control_state,
+                            stop,
+                        ) {
+                            warn!("Dropping TCP connection due to error: {}", e);
+                        }
+                    }
+                    Job::Control(socket) => {
+                        if let Err(e) =
+                            control::serve(socket, control_state, &config, home.as_deref(), uid, &**reload)
+                        {
+                            warn!("Dropping control connection due to error: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        while !stop.load(Ordering::Relaxed) {
+            let mut pollfds =
+                vec![libc::pollfd { fd: wake_read.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+            let listener_pollfd_index = listener.as_ref().map(|listener| {
+                pollfds.push(libc::pollfd {
+                    fd: listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                pollfds.len() - 1
+            });
+            let tcp_pollfd_index = tcp_listener.as_ref().map(|tcp_listener| {
+                pollfds.push(libc::pollfd {
+                    fd: tcp_listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                pollfds.len() - 1
+            });
+            let control_pollfd_index = control_listener.as_ref().map(|control_listener| {
+                pollfds.push(libc::pollfd {
+                    fd: control_listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                pollfds.len() - 1
+            });
+
+            // No timeout: we only wake up when the listener(s) have a connection to accept or the
+            // wake pipe tells us a termination signal arrived, so there's nothing useful to do in
+            // between.
+            let rc = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            match rc {
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        warn!("poll() failed: {}", e);
+                    }
+                    continue;
+                }
+                0 => continue,
+                _ => (),
+            }
+
+            if let (Some(listener), Some(index)) = (&listener, listener_pollfd_index) {
+                if pollfds[index].revents & libc::POLLIN != 0 {
+                    match listener.accept() {
+                        Ok((socket, _addr)) => {
+                            debug!("Connection accepted");
+                            if job_sender.send(Job::Unix(socket)).is_err() {
+                                warn!("Worker pool is gone; dropping connection");
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => warn!("Failed to accept connection: {}", e),
+                    };
                 }
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                std::thread::sleep(Duration::from_millis(100));
+
+            if let (Some(tcp_listener), Some(index)) = (&tcp_listener, tcp_pollfd_index) {
+                if pollfds[index].revents & libc::POLLIN != 0 {
+                    match tcp_listener.accept() {
+                        Ok((socket, addr)) => {
+                            debug!("TCP connection accepted from {}", addr);
+                            if job_sender.send(Job::Tcp(socket)).is_err() {
+                                warn!("Worker pool is gone; dropping TCP connection");
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => warn!("Failed to accept TCP connection: {}", e),
+                    };
+                }
             }
-            Err(e) => warn!("Failed to accept connection: {}", e),
-        };
-    }
+
+            if let (Some(control_listener), Some(index)) = (&control_listener, control_pollfd_index) {
+                if pollfds[index].revents & libc::POLLIN != 0 {
+                    match control_listener.accept() {
+                        Ok((socket, _addr)) => {
+                            debug!("Control connection accepted");
+                            if job_sender.send(Job::Control(socket)).is_err() {
+                                warn!("Worker pool is gone; dropping control connection");
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => warn!("Failed to accept control connection: {}", e),
+                    };
+                }
+            }
+
+            if pollfds[0].revents & libc::POLLIN != 0 {
+                // A termination signal arrived.  The signal handler wrote this byte itself, so the
+                // signal thread in `setup_signals` may not have set `stop` yet: leave the loop now
+                // instead of re-checking `stop` and going back to an unbounded poll().
+                let mut discard = [0u8; 64];
+                while wake_read.read(&mut discard).map(|n| n > 0).unwrap_or(false) {}
+                break;
+            }
+        }
+
+        // Dropping the sender closes the channel, so each worker's `recv` returns an error and the
+        // thread exits; `thread::scope` then joins every worker below before we proceed with
+        // cleanup.
+        drop(job_sender);
+    });
     debug!("Main loop exited");
 
-    handle.join().map_err(|_| format!("Failed to wait for signals"))
+    handle
+        .join()
+        .map_err(|_| Error::new(ErrorCategory::Internal, "Failed to wait for signals".to_owned()))
 }
 
 /// Waits for `path` to exist for a maximum period of time using operation `op`.
@@ -24,9 +24,310 @@
 //! Serves a Unix domain socket that proxies connections to any valid SSH agent provided by sshd.
 
 use getoptsargs::prelude::*;
+use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{env, io};
 
+mod daemon;
+
+/// Process exit status for an unexpected internal error (e.g. a panicked thread).
+const EXIT_INTERNAL: i32 = 1;
+
+/// Process exit status for invalid command-line arguments or configuration.
+const EXIT_CONFIG: i32 = 2;
+
+/// Process exit status for a failure to bind or listen on `--socket-path`.
+const EXIT_SOCKET: i32 = 3;
+
+/// Process exit status for "none of the configured agent directories could be read"; `--check` and
+/// `--status` also report it when no live agent socket was found.
+const EXIT_NO_AGENT_DIR: i32 = 4;
+
+/// Maps a library error category to the process exit status we report for it.
+fn exit_code_for(category: ssh_agent_switcher::ErrorCategory) -> i32 {
+    match category {
+        ssh_agent_switcher::ErrorCategory::Internal => EXIT_INTERNAL,
+        ssh_agent_switcher::ErrorCategory::Socket => EXIT_SOCKET,
+        ssh_agent_switcher::ErrorCategory::NoAgentDir => EXIT_NO_AGENT_DIR,
+    }
+}
+
+/// Unwraps a `Result` coming from command-line/configuration resolution, reporting the error and
+/// returning from `app_main` with `EXIT_CONFIG` on failure.
+macro_rules! config {
+    ( $result:expr ) => {
+        match $result {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return Ok(EXIT_CONFIG);
+            }
+        }
+    };
+}
+
+/// Where the switcher should send its log output.
+enum LogTarget {
+    /// The process' own stderr (captured by a supervisor such as journald under systemd).
+    Stderr,
+
+    /// The system log, via the `syslog` crate.
+    Syslog,
+
+    /// A plain file, opened in append mode.
+    File(PathBuf),
+}
+
+/// Shell syntax to use when printing `SSH_AUTH_SOCK`/`SSH_AGENT_SWITCHER_PID` assignments for
+/// `-s`/`-c`, matching the two syntaxes `ssh-agent` itself supports.
+#[derive(Clone, Copy)]
+enum ShellSyntax {
+    /// Bourne-compatible `VAR=value; export VAR;` syntax.
+    Bourne,
+
+    /// C-shell `setenv VAR value;` syntax.
+    Csh,
+}
+
+/// Output format for the `--status` subcommand.
+#[derive(Clone, Copy)]
+enum StatusFormat {
+    /// Human-readable text, one candidate per line.
+    Text,
+
+    /// A single JSON object, for monitoring scripts to parse.
+    Json,
+}
+
+/// Gets the value of the `--format` flag, computing a default if necessary.
+fn get_status_format(matches: &Matches) -> Result<StatusFormat> {
+    match matches.opt_str("format").as_deref() {
+        None | Some("text") => Ok(StatusFormat::Text),
+        Some("json") => Ok(StatusFormat::Json),
+        Some(other) => bail!("Invalid --format value '{}': expected text or json", other),
+    }
+}
+
+/// Escapes `s` for embedding as a JSON string, including the surrounding quotes.
+fn json_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats `status` as requested by `format`.
+fn format_status(status: &ssh_agent_switcher::Status, format: StatusFormat) -> String {
+    match format {
+        StatusFormat::Text => {
+            let mut out = String::new();
+            for candidate in &status.candidates {
+                let marker = if Some(&candidate.path) == status.selected.as_ref() { "*" } else { " " };
+                let alive = if candidate.alive { "alive" } else { "dead" };
+                out.push_str(&format!("{} {} {}\n", marker, alive, candidate.path.display()));
+            }
+            match &status.selected {
+                Some(path) => out.push_str(&format!("Selected: {}\n", path.display())),
+                None => out.push_str("Selected: none\n"),
+            }
+            out
+        }
+        StatusFormat::Json => {
+            let candidates = status
+                .candidates
+                .iter()
+                .map(|c| {
+                    format!(
+                        "{{\"path\":{},\"alive\":{}}}",
+                        json_quote(&c.path.display().to_string()),
+                        c.alive
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+            let selected = match &status.selected {
+                Some(path) => json_quote(&path.display().to_string()),
+                None => "null".to_owned(),
+            };
+            format!("{{\"selected\":{},\"candidates\":[{}]}}\n", selected, candidates)
+        }
+    }
+}
+
+/// Computes the request-filtering mode requested via `--readonly`/`--sign-only` from their raw
+/// presence, so that `SIGHUP` reload can recompute it without holding on to a `Matches`.
+fn policy_from(readonly: bool, sign_only: bool) -> Result<Option<ssh_agent_switcher::policy::Mode>> {
+    match (readonly, sign_only) {
+        (true, true) => bail!("--readonly and --sign-only cannot be given at the same time"),
+        (true, false) => Ok(Some(ssh_agent_switcher::policy::Mode::ReadOnly)),
+        (false, true) => Ok(Some(ssh_agent_switcher::policy::Mode::SignOnly)),
+        (false, false) => Ok(None),
+    }
+}
+
+/// Gets the request-filtering mode requested via `--readonly`/`--sign-only`, if either was given.
+fn get_policy(matches: &Matches) -> Result<Option<ssh_agent_switcher::policy::Mode>> {
+    policy_from(matches.opt_present("readonly"), matches.opt_present("sign-only"))
+}
+
+/// Computes whether `--aggregate` was requested, rejecting it alongside `--readonly`/`--sign-only`
+/// since a fan-out multiplexer and a request filter are two different ways of reinterpreting the
+/// same requests; so that `SIGHUP` reload can recompute it without holding on to a `Matches`.
+fn aggregate_from(aggregate: bool, readonly: bool, sign_only: bool) -> Result<bool> {
+    if aggregate && (readonly || sign_only) {
+        bail!("--aggregate cannot be given at the same time as --readonly or --sign-only");
+    }
+    Ok(aggregate)
+}
+
+/// Gets whether `--aggregate` was requested.
+fn get_aggregate(matches: &Matches) -> Result<bool> {
+    aggregate_from(
+        matches.opt_present("aggregate"),
+        matches.opt_present("readonly"),
+        matches.opt_present("sign-only"),
+    )
+}
+
+/// Computes whether `--failover` was requested, rejecting it alongside `--readonly`/`--sign-only`/
+/// `--aggregate`: combining backend reselection with per-message filtering or fan-out is left as
+/// future work, same as `--aggregate` itself does not yet compose with those two; so that `SIGHUP`
+/// reload can recompute it without holding on to a `Matches`.
+fn failover_from(failover: bool, readonly: bool, sign_only: bool, aggregate: bool) -> Result<bool> {
+    if failover && (readonly || sign_only || aggregate) {
+        bail!(
+            "--failover cannot be given at the same time as --readonly, --sign-only, or --aggregate"
+        );
+    }
+    Ok(failover)
+}
+
+/// Gets whether `--failover` was requested.
+fn get_failover(matches: &Matches) -> Result<bool> {
+    failover_from(
+        matches.opt_present("failover"),
+        matches.opt_present("readonly"),
+        matches.opt_present("sign-only"),
+        matches.opt_present("aggregate"),
+    )
+}
+
+/// Gets the value of the `--failover-read-timeout` flag, computing a default if necessary.
+fn get_failover_read_timeout(matches: &Matches) -> Result<Duration> {
+    if let Some(s) = matches.opt_str("failover-read-timeout") {
+        let millis: u64 =
+            s.parse().map_err(|e| format!("Invalid --failover-read-timeout value {}: {}", s, e))?;
+        return Ok(Duration::from_millis(millis));
+    }
+
+    Ok(ssh_agent_switcher::DEFAULT_FAILOVER_READ_TIMEOUT)
+}
+
+/// Computes the value of the `--idle-timeout` flag from its raw value; `None` (the default, since
+/// this cap is opt-in) if the flag was not given, so that `SIGHUP` reload can recompute it without
+/// holding on to a `Matches`.
+fn idle_timeout_from(arg: Option<&str>) -> Result<Option<Duration>> {
+    match arg {
+        Some(s) => {
+            let millis: u64 = s.parse().map_err(|e| format!("Invalid --idle-timeout value {}: {}", s, e))?;
+            Ok(Some(Duration::from_millis(millis)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Gets the value of the `--idle-timeout` flag, or `None` if it was not given.
+fn get_idle_timeout(matches: &Matches) -> Result<Option<Duration>> {
+    idle_timeout_from(matches.opt_str("idle-timeout").as_deref())
+}
+
+/// Computes the value of the `--total-timeout` flag from its raw value; `None` (the default, since
+/// this cap is opt-in) if the flag was not given, so that `SIGHUP` reload can recompute it without
+/// holding on to a `Matches`.
+fn total_timeout_from(arg: Option<&str>) -> Result<Option<Duration>> {
+    match arg {
+        Some(s) => {
+            let millis: u64 = s.parse().map_err(|e| format!("Invalid --total-timeout value {}: {}", s, e))?;
+            Ok(Some(Duration::from_millis(millis)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Gets the value of the `--total-timeout` flag, or `None` if it was not given.
+fn get_total_timeout(matches: &Matches) -> Result<Option<Duration>> {
+    total_timeout_from(matches.opt_str("total-timeout").as_deref())
+}
+
+/// Gets the value of the `--log-format` flag, computing a default if necessary.
+fn get_log_format(matches: &Matches) -> Result<ssh_agent_switcher::event::LogFormat> {
+    match matches.opt_str("log-format").as_deref() {
+        None | Some("text") => Ok(ssh_agent_switcher::event::LogFormat::Text),
+        Some("json") => Ok(ssh_agent_switcher::event::LogFormat::Json),
+        Some(other) => bail!("Invalid --log-format value '{}': expected text or json", other),
+    }
+}
+
+/// Gets the eval-mode syntax requested via `-s`/`-c`, if either was given.
+fn get_eval_syntax(matches: &Matches) -> Result<Option<ShellSyntax>> {
+    match (matches.opt_present("s"), matches.opt_present("c")) {
+        (true, true) => bail!("-s and -c cannot be given at the same time"),
+        (true, false) => Ok(Some(ShellSyntax::Bourne)),
+        (false, true) => Ok(Some(ShellSyntax::Csh)),
+        (false, false) => Ok(None),
+    }
+}
+
+/// Formats the `SSH_AUTH_SOCK`/`SSH_AGENT_SWITCHER_PID` assignments for `socket_path` and `pid` in
+/// the given shell `syntax`, mirroring the output `ssh-agent -s`/`-c` produce for their own socket
+/// so that tooling which already parses that output keeps working unmodified.  `socket_path` is
+/// `None` when `--listen` replaced the primary Unix socket with a TCP endpoint, in which case there
+/// is no `SSH_AUTH_SOCK` to export and callers are expected to rely on `tcp_port` instead.  When
+/// `tcp_port` is set, an `SSH_AGENT_SWITCHER_TCP_PORT` assignment is appended so callers can learn
+/// the port that was chosen for `--listen`/`--listen-tcp` when none was given explicitly.
+fn format_eval_output(
+    syntax: ShellSyntax,
+    socket_path: Option<&str>,
+    pid: libc::pid_t,
+    tcp_port: Option<u16>,
+) -> String {
+    let mut out = String::new();
+    if let Some(socket_path) = socket_path {
+        out.push_str(&match syntax {
+            ShellSyntax::Bourne => format!("SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;\n", socket_path),
+            ShellSyntax::Csh => format!("setenv SSH_AUTH_SOCK {};\n", socket_path),
+        });
+    }
+    out.push_str(&match syntax {
+        ShellSyntax::Bourne => {
+            format!("SSH_AGENT_SWITCHER_PID={}; export SSH_AGENT_SWITCHER_PID;\n", pid)
+        }
+        ShellSyntax::Csh => format!("setenv SSH_AGENT_SWITCHER_PID {};\n", pid),
+    });
+
+    if let Some(port) = tcp_port {
+        out.push_str(&match syntax {
+            ShellSyntax::Bourne => format!(
+                "SSH_AGENT_SWITCHER_TCP_PORT={}; export SSH_AGENT_SWITCHER_TCP_PORT;\n",
+                port
+            ),
+            ShellSyntax::Csh => format!("setenv SSH_AGENT_SWITCHER_TCP_PORT {};\n", port),
+        });
+    }
+
+    out
+}
+
 /// Checks if the required `name` variable is present and returns its value.
 fn get_required_env_var(name: &str) -> Result<String> {
     match env::var(name) {
@@ -43,15 +344,21 @@ fn default_agents_dirs() -> Result<Vec<PathBuf>> {
     Ok(vec![PathBuf::from(format!("{}/.ssh/agent", home)), PathBuf::from("/tmp")])
 }
 
-/// Gets the value of the `--agents-dirs` flag, computing a default if necessary.
-fn get_agents_dirs(matches: &Matches) -> Result<Vec<PathBuf>> {
-    if let Some(s) = matches.opt_str("agents-dirs") {
+/// Computes the value of the `--agents-dirs` flag from its raw value, computing a default if
+/// `arg` is `None`; so that `SIGHUP` reload can recompute it without holding on to a `Matches`.
+fn agents_dirs_from(arg: Option<&str>) -> Result<Vec<PathBuf>> {
+    if let Some(s) = arg {
         return Ok(s.split(":").into_iter().map(PathBuf::from).collect());
     }
 
     default_agents_dirs()
 }
 
+/// Gets the value of the `--agents-dirs` flag, computing a default if necessary.
+fn get_agents_dirs(matches: &Matches) -> Result<Vec<PathBuf>> {
+    agents_dirs_from(matches.opt_str("agents-dirs").as_deref())
+}
+
 /// Returns the default value of the `--socket-path` flag.
 fn default_socket_path() -> Result<PathBuf> {
     let user = get_required_env_var("USER")?;
@@ -59,12 +366,259 @@ fn default_socket_path() -> Result<PathBuf> {
 }
 
 /// Gets the value of the `--socket-path` flag, computing a default if necessary.
-fn get_socket_path(matches: &Matches) -> Result<PathBuf> {
-    if let Some(s) = matches.opt_str("socket-path") {
+///
+/// A value beginning with `@` names a Linux abstract socket instead of a filesystem path, as
+/// `ip netns`/`systemd`-style tooling commonly writes it.
+fn get_socket_spec(matches: &Matches) -> Result<ssh_agent_switcher::SocketSpec> {
+    let raw = match matches.opt_str("socket-path") {
+        Some(s) => s,
+        None => return Ok(ssh_agent_switcher::SocketSpec::Path(default_socket_path()?)),
+    };
+
+    if let Some(name) = raw.strip_prefix('@') {
+        return Ok(ssh_agent_switcher::SocketSpec::Abstract(name.to_owned()));
+    }
+
+    Ok(ssh_agent_switcher::SocketSpec::Path(PathBuf::from(raw)))
+}
+
+/// Returns the directory to use for state that should survive a reboot (logs), following the XDG
+/// base directory spec with a fallback for systems that don't set `XDG_STATE_HOME`.
+fn default_state_dir() -> Result<PathBuf> {
+    if let Ok(dir) = env::var("XDG_STATE_HOME") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let home = get_required_env_var("HOME")?;
+    Ok(PathBuf::from(format!("{}/.local/state", home)))
+}
+
+/// Returns the directory to use for state that should not survive a reboot (the PID file),
+/// following the XDG base directory spec and falling back to the state directory when
+/// `XDG_RUNTIME_DIR` is not set.
+fn default_runtime_dir() -> Result<PathBuf> {
+    if let Ok(dir) = env::var("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    default_state_dir()
+}
+
+/// Gets the value of the `--pid-file` flag, computing an XDG-based default if necessary.
+fn get_pid_file(matches: &Matches) -> Result<PathBuf> {
+    if let Some(s) = matches.opt_str("pid-file") {
         return Ok(PathBuf::from(s));
     }
 
-    default_socket_path()
+    Ok(default_runtime_dir()?.join("ssh-agent-switcher.pid"))
+}
+
+/// Gets the value of the `--log-target`/`--log-file` flags, computing a default if necessary.
+///
+/// When daemonizing, the default is a file under the XDG state directory so that the log survives
+/// across runs even without a supervisor attached; `--log-target syslog` opts into routing through
+/// the system log instead, which is more appropriate when many per-user instances run on a shared
+/// host.  In the foreground, the default remains the process' own stderr.
+fn get_log_target(matches: &Matches, daemon: bool) -> Result<LogTarget> {
+    if let Some(s) = matches.opt_str("log-file") {
+        return Ok(LogTarget::File(PathBuf::from(s)));
+    }
+
+    if let Some(s) = matches.opt_str("log-target") {
+        return match s.as_str() {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            other => bail!("Invalid --log-target value '{}': expected stderr or syslog", other),
+        };
+    }
+
+    if daemon {
+        return Ok(LogTarget::File(default_state_dir()?.join("ssh-agent-switcher.log")));
+    }
+
+    Ok(LogTarget::Stderr)
+}
+
+/// Initializes the `log` backend to send output to `target`.
+fn init_logging(target: LogTarget) -> Result<()> {
+    match target {
+        LogTarget::Stderr => {
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+                .init();
+        }
+        LogTarget::File(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("Cannot open log file {}: {}", path.display(), e))?;
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .init();
+        }
+        LogTarget::Syslog => {
+            syslog::init(syslog::Facility::LOG_USER, log::LevelFilter::Info, Some("ssh-agent-switcher"))
+                .map_err(|e| format!("Cannot initialize syslog logging: {}", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes the value of the `--probe-timeout` flag from its raw presence/value, returning `None`
+/// when `no_probe` is set and the default timeout otherwise; so that `SIGHUP` reload can recompute
+/// it without holding on to a `Matches`.
+fn probe_timeout_from(no_probe: bool, arg: Option<&str>) -> Result<Option<Duration>> {
+    if no_probe {
+        return Ok(None);
+    }
+
+    if let Some(s) = arg {
+        let millis: u64 =
+            s.parse().map_err(|e| format!("Invalid --probe-timeout value {}: {}", s, e))?;
+        return Ok(Some(Duration::from_millis(millis)));
+    }
+
+    Ok(Some(ssh_agent_switcher::DEFAULT_PROBE_TIMEOUT))
+}
+
+/// Gets the value of the `--probe-timeout` flag, returning `None` when `--no-probe` was given and
+/// the default timeout otherwise.
+fn get_probe_timeout(matches: &Matches) -> Result<Option<Duration>> {
+    probe_timeout_from(matches.opt_present("no-probe"), matches.opt_str("probe-timeout").as_deref())
+}
+
+/// Computes the value of the `--scan-concurrency` flag from its raw value, computing a default if
+/// `arg` is `None`; so that `SIGHUP` reload can recompute it without holding on to a `Matches`.
+fn scan_concurrency_from(arg: Option<&str>) -> Result<usize> {
+    if let Some(s) = arg {
+        let n: usize = s.parse().map_err(|e| format!("Invalid --scan-concurrency value {}: {}", s, e))?;
+        if n == 0 {
+            bail!("--scan-concurrency must be at least 1");
+        }
+        return Ok(n);
+    }
+
+    Ok(ssh_agent_switcher::DEFAULT_SCAN_CONCURRENCY)
+}
+
+/// Gets the value of the `--scan-concurrency` flag, computing a default if necessary.
+fn get_scan_concurrency(matches: &Matches) -> Result<usize> {
+    scan_concurrency_from(matches.opt_str("scan-concurrency").as_deref())
+}
+
+/// Gets the value of the `--connection-concurrency` flag, computing a default if necessary.
+fn get_connection_concurrency(matches: &Matches) -> Result<usize> {
+    if let Some(s) = matches.opt_str("connection-concurrency") {
+        let n: usize =
+            s.parse().map_err(|e| format!("Invalid --connection-concurrency value {}: {}", s, e))?;
+        if n == 0 {
+            bail!("--connection-concurrency must be at least 1");
+        }
+        return Ok(n);
+    }
+
+    Ok(ssh_agent_switcher::DEFAULT_CONNECTION_CONCURRENCY)
+}
+
+/// Gets the fallback-agent configuration requested on the command line, if any.
+fn get_fallback_agent(
+    matches: &Matches,
+    agents_dirs: &[PathBuf],
+) -> Result<Option<ssh_agent_switcher::fallback::FallbackConfig>> {
+    if let Some(s) = matches.opt_str("fallback-socket") {
+        return Ok(Some(ssh_agent_switcher::fallback::FallbackConfig::ExternalSocket {
+            path: PathBuf::from(s),
+        }));
+    }
+
+    if matches.opt_present("spawn-agent") {
+        // Spawn into the first configured agents-dir, rather than a scratch directory, so the
+        // socket we create lives where a real forwarded agent would have put it.
+        let dir = agents_dirs
+            .first()
+            .ok_or_else(|| "--spawn-agent requires at least one configured agents-dir".to_owned())?
+            .clone();
+        return Ok(Some(ssh_agent_switcher::fallback::FallbackConfig::SpawnSshAgent { dir }));
+    }
+
+    if matches.opt_present("fallback-agent") {
+        return Ok(Some(ssh_agent_switcher::fallback::FallbackConfig::SpawnSshAgent {
+            dir: env::temp_dir(),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Gets the value of the `--control-socket` flag, if given.
+fn get_control_socket(matches: &Matches) -> Result<Option<PathBuf>> {
+    Ok(matches.opt_str("control-socket").map(PathBuf::from))
+}
+
+/// Gets the `--listen-tcp` configuration requested on the command line, if any, reading the
+/// paired `--token-file` that it requires.
+fn get_listen_tcp(matches: &Matches) -> Result<Option<(ssh_agent_switcher::tcp::TcpConfig, Vec<u8>)>> {
+    let addr = match matches.opt_str("listen-tcp") {
+        Some(addr) => addr,
+        None => {
+            // `--listen` also consumes `--token-file`, so a lonely one is only an error if neither
+            // flag that requires it was given.
+            if matches.opt_present("token-file") && !matches.opt_present("listen") {
+                bail!("--token-file requires --listen-tcp or --listen");
+            }
+            return Ok(None);
+        }
+    };
+
+    let token_file = match matches.opt_str("token-file") {
+        Some(s) => PathBuf::from(s),
+        None => bail!("--listen-tcp requires --token-file"),
+    };
+    let token = ssh_agent_switcher::tcp::read_token_file(&token_file)?;
+
+    let (host, port) = parse_listen_addr(&addr, "--listen-tcp")?;
+    Ok(Some((ssh_agent_switcher::tcp::TcpConfig { host, port }, token)))
+}
+
+/// Gets the `--listen` configuration requested on the command line, if any: this replaces the
+/// primary Unix socket with a TCP endpoint, reusing the same token-gated authentication
+/// `--listen-tcp` already requires rather than adding a second, unauthenticated way to accept TCP
+/// connections.
+fn get_listen(matches: &Matches) -> Result<Option<(ssh_agent_switcher::tcp::TcpConfig, Vec<u8>)>> {
+    let addr = match matches.opt_str("listen") {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+
+    if matches.opt_present("socket-path") {
+        bail!("--listen and --socket-path cannot be given at the same time");
+    }
+    if matches.opt_present("listen-tcp") {
+        bail!("--listen and --listen-tcp cannot be given at the same time");
+    }
+
+    let token_file = match matches.opt_str("token-file") {
+        Some(s) => PathBuf::from(s),
+        None => bail!("--listen requires --token-file"),
+    };
+    let token = ssh_agent_switcher::tcp::read_token_file(&token_file)?;
+
+    let (host, port) = parse_listen_addr(&addr, "--listen")?;
+    Ok(Some((ssh_agent_switcher::tcp::TcpConfig { host, port }, token)))
+}
+
+/// Parses a `host[:port]` address as given to `--listen`/`--listen-tcp`, naming `flag` in the error
+/// message on an invalid port.
+fn parse_listen_addr(addr: &str, flag: &str) -> Result<(String, Option<u16>)> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) => {
+            let port: u16 =
+                port.parse().map_err(|e| format!("Invalid port in {} {}: {}", flag, addr, e))?;
+            Ok((host.to_owned(), Some(port)))
+        }
+        None => Ok((addr.to_owned(), None)),
+    }
 }
 
 fn app_extra_help(output: &mut dyn io::Write) -> io::Result<()> {
@@ -101,20 +655,326 @@ fn app_setup(builder: Builder) -> Builder {
             "colon-separated list of directories where to look for running agents",
             "dir1:..:dirn",
         )
-        .optopt("", "socket-path", "path to the socket to listen on", "path")
+        .optopt(
+            "",
+            "socket-path",
+            "path to the socket to listen on; a value starting with @ names a Linux abstract socket",
+            "path",
+        )
+        .optopt(
+            "",
+            "probe-timeout",
+            "milliseconds to wait for a candidate agent socket to answer a liveness probe",
+            "millis",
+        )
+        .optflag("", "no-probe", "skip the liveness probe and accept the first connectable socket")
+        .optopt(
+            "",
+            "scan-concurrency",
+            "maximum number of candidate sockets to probe at once within a directory",
+            "n",
+        )
+        .optopt(
+            "",
+            "connection-concurrency",
+            "maximum number of client connections to handle at once",
+            "n",
+        )
+        .optflag(
+            "",
+            "fallback-agent",
+            "spawn a local ssh-agent to use until a forwarded agent is found",
+        )
+        .optflag(
+            "",
+            "spawn-agent",
+            "like --fallback-agent, but places the spawned agent's socket in the first agents-dir",
+        )
+        .optopt(
+            "",
+            "fallback-socket",
+            "socket of an already-running agent to use until a forwarded agent is found",
+            "path",
+        )
+        .optflag("", "daemon", "detach from the terminal and run in the background")
+        .optopt(
+            "",
+            "pid-file",
+            "path to the file where the running daemon's PID is recorded",
+            "path",
+        )
+        .optopt("", "log-file", "path to a file to append log output to", "path")
+        .optopt("", "log-target", "where to send log output: stderr or syslog", "target")
+        .optflag(
+            "",
+            "check",
+            "report which agent socket would be selected and exit without serving",
+        )
+        .optflag(
+            "",
+            "status",
+            "report every discovered agent socket, its liveness, and which one is selected",
+        )
+        .optopt("", "format", "output format for --status: text (default) or json", "format")
+        .optflag(
+            "s",
+            "",
+            "daemonize and print Bourne shell commands to set SSH_AUTH_SOCK, as ssh-agent -s does",
+        )
+        .optflag(
+            "c",
+            "",
+            "daemonize and print C-shell commands to set SSH_AUTH_SOCK, as ssh-agent -c does",
+        )
+        .optopt(
+            "",
+            "control-socket",
+            "also listen on this path for control commands (list, status, reload)",
+            "path",
+        )
+        .optopt(
+            "",
+            "listen-tcp",
+            "also listen on this host[:port] for agent forwarding over TCP, gated by --token-file",
+            "host[:port]",
+        )
+        .optopt(
+            "",
+            "listen",
+            "serve the primary agent socket on this host[:port] over TCP instead of a Unix socket",
+            "host[:port]",
+        )
+        .optopt(
+            "",
+            "token-file",
+            "file holding the pre-shared token required to use --listen-tcp or --listen",
+            "path",
+        )
+        .optflag(
+            "",
+            "proxy-protocol",
+            "expect --listen-tcp/--listen connections to start with a PROXY protocol v2 header, \
+             as emitted by a TCP forwarder (socat, haproxy, ssh -L) in front of them",
+        )
+        .optflag(
+            "",
+            "readonly",
+            "reject requests that would add, remove, or otherwise mutate the agent's keys",
+        )
+        .optflag(
+            "",
+            "sign-only",
+            "like --readonly, but also rejects listing identities: only signing is allowed",
+        )
+        .optflag(
+            "",
+            "aggregate",
+            "expose the union of identities from every live agent in --agents-dirs, routing sign \
+             requests to whichever one holds the requested key",
+        )
+        .optflag(
+            "",
+            "failover",
+            "reselect and reconnect to another live backend if the active one dies mid-connection, \
+             instead of letting the client's connection fail",
+        )
+        .optopt(
+            "",
+            "failover-read-timeout",
+            "milliseconds to wait for the active backend to answer before closing the connection",
+            "millis",
+        )
+        .optopt(
+            "",
+            "idle-timeout",
+            "close a proxied connection after this many milliseconds with no traffic; disabled \
+             unless given",
+            "millis",
+        )
+        .optopt(
+            "",
+            "total-timeout",
+            "close a proxied connection after this many milliseconds total, regardless of \
+             activity; disabled unless given",
+            "millis",
+        )
+        .optopt(
+            "",
+            "log-format",
+            "format for discovery and connection log events: text (default) or json",
+            "format",
+        )
 }
 
 fn app_main(matches: Matches) -> Result<i32> {
-    let socket_path = get_socket_path(&matches)?;
-    let agents_dirs = get_agents_dirs(&matches)?;
+    let agents_dirs = config!(get_agents_dirs(&matches));
+    let probe_timeout = config!(get_probe_timeout(&matches));
+    let scan_concurrency = config!(get_scan_concurrency(&matches));
+    let connection_concurrency = config!(get_connection_concurrency(&matches));
+
+    if matches.opt_present("check") {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+        return Ok(match ssh_agent_switcher::check(&agents_dirs, probe_timeout, scan_concurrency) {
+            Some(path) => {
+                println!("Would select agent socket: {}", path.display());
+                0
+            }
+            None => {
+                eprintln!(
+                    "No live agent socket found in {}",
+                    agents_dirs
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<String>>()
+                        .join(":")
+                );
+                EXIT_NO_AGENT_DIR
+            }
+        });
+    }
+
+    if matches.opt_present("status") {
+        let format = config!(get_status_format(&matches));
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+        let status = ssh_agent_switcher::status(&agents_dirs, probe_timeout, scan_concurrency);
+        print!("{}", format_status(&status, format));
+        return Ok(if status.selected.is_some() { 0 } else { EXIT_NO_AGENT_DIR });
+    }
+
+    let fallback_agent = config!(get_fallback_agent(&matches, &agents_dirs));
+    let policy = config!(get_policy(&matches));
+    let aggregate = config!(get_aggregate(&matches));
+    let failover = config!(get_failover(&matches));
+    let failover_read_timeout = config!(get_failover_read_timeout(&matches));
+    let idle_timeout = config!(get_idle_timeout(&matches));
+    let total_timeout = config!(get_total_timeout(&matches));
+    let proxy_protocol = matches.opt_present("proxy-protocol");
+    let log_format = config!(get_log_format(&matches));
+    let eval_syntax = config!(get_eval_syntax(&matches));
+    // `-s`/`-c` imply `--daemon`: like `ssh-agent -s`, there would be no way to hand control back
+    // to the calling shell's `eval` otherwise.
+    let daemon = matches.opt_present("daemon") || eval_syntax.is_some();
+    let pid_file = config!(get_pid_file(&matches));
+    let log_target = config!(get_log_target(&matches, daemon));
+
+    let control_socket = config!(get_control_socket(&matches));
+
+    // Bind the TCP listener(s) (if requested) before daemonizing so that an auto-picked port is
+    // known in time to report it via the shell-eval output below.  `--listen` replaces the primary
+    // Unix socket outright, so `socket_spec` stays `None` in that case; otherwise the Unix socket is
+    // always bound, optionally alongside `--listen-tcp`'s separate forwarding listener.
+    let (socket_spec, bound_tcp) = match config!(get_listen(&matches)) {
+        Some((tcp_config, token)) => {
+            let bound = config!(ssh_agent_switcher::tcp::bind(&tcp_config, token));
+            (None, Some(bound))
+        }
+        None => {
+            let spec = config!(get_socket_spec(&matches));
+            let bound_tcp = match config!(get_listen_tcp(&matches)) {
+                Some((tcp_config, token)) => {
+                    Some(config!(ssh_agent_switcher::tcp::bind(&tcp_config, token)))
+                }
+                None => None,
+            };
+            (Some(spec), bound_tcp)
+        }
+    };
+    let tcp_port = bound_tcp.as_ref().map(|bound| bound.port);
+
+    // Keep the lock on the PID file alive for the whole run: dropping it would let another
+    // instance start up and race us for the agent socket.
+    let _pid_lock = if daemon {
+        let on_ready: Option<Box<dyn Fn(libc::pid_t)>> = eval_syntax.map(|syntax| {
+            let socket_spec = socket_spec.clone();
+            Box::new(move |pid: libc::pid_t| {
+                let socket_path = socket_spec.as_ref().map(|spec| spec.to_string());
+                print!("{}", format_eval_output(syntax, socket_path.as_deref(), pid, tcp_port))
+            }) as Box<dyn Fn(libc::pid_t)>
+        });
+        match daemon::daemonize(&pid_file, on_ready.as_deref()) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return Ok(EXIT_INTERNAL);
+            }
+        }
+    } else {
+        None
+    };
+
+    config!(init_logging(log_target));
+
+    let initial_config = ssh_agent_switcher::Config {
+        agents_dirs,
+        probe_timeout,
+        scan_concurrency,
+        policy,
+        aggregate,
+        failover,
+        failover_read_timeout,
+        idle_timeout,
+        total_timeout,
+        proxy_protocol,
+    };
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Captured as owned, primitive values rather than `matches` itself, since it isn't known
+    // whether `Matches` is `Send`/`Sync`; re-running the same resolution logic against these on
+    // every `SIGHUP` is what lets a reload pick up edits to, say, an agents-dir symlink without
+    // discarding any flag the user explicitly passed at startup.
+    let reload_agents_dirs_arg = matches.opt_str("agents-dirs");
+    let reload_no_probe = matches.opt_present("no-probe");
+    let reload_probe_timeout_arg = matches.opt_str("probe-timeout");
+    let reload_scan_concurrency_arg = matches.opt_str("scan-concurrency");
+    let reload_readonly = matches.opt_present("readonly");
+    let reload_sign_only = matches.opt_present("sign-only");
+    let reload_aggregate = matches.opt_present("aggregate");
+    let reload_failover = matches.opt_present("failover");
+    let reload_failover_read_timeout_arg = matches.opt_str("failover-read-timeout");
+    let reload_idle_timeout_arg = matches.opt_str("idle-timeout");
+    let reload_total_timeout_arg = matches.opt_str("total-timeout");
+    let reload_proxy_protocol = matches.opt_present("proxy-protocol");
+    let reload: std::sync::Arc<
+        dyn Fn() -> std::result::Result<ssh_agent_switcher::Config, String> + Send + Sync,
+    > = std::sync::Arc::new(move || {
+        Ok(ssh_agent_switcher::Config {
+            agents_dirs: agents_dirs_from(reload_agents_dirs_arg.as_deref())?,
+            probe_timeout: probe_timeout_from(reload_no_probe, reload_probe_timeout_arg.as_deref())?,
+            scan_concurrency: scan_concurrency_from(reload_scan_concurrency_arg.as_deref())?,
+            policy: policy_from(reload_readonly, reload_sign_only)?,
+            aggregate: aggregate_from(reload_aggregate, reload_readonly, reload_sign_only)?,
+            failover: failover_from(
+                reload_failover,
+                reload_readonly,
+                reload_sign_only,
+                reload_aggregate,
+            )?,
+            failover_read_timeout: match &reload_failover_read_timeout_arg {
+                Some(s) => Duration::from_millis(
+                    s.parse().map_err(|e| format!("Invalid --failover-read-timeout value {}: {}", s, e))?,
+                ),
+                None => ssh_agent_switcher::DEFAULT_FAILOVER_READ_TIMEOUT,
+            },
+            idle_timeout: idle_timeout_from(reload_idle_timeout_arg.as_deref())?,
+            total_timeout: total_timeout_from(reload_total_timeout_arg.as_deref())?,
+            proxy_protocol: reload_proxy_protocol,
+        })
+    });
 
-    match ssh_agent_switcher::run(socket_path, &agents_dirs) {
+    match ssh_agent_switcher::run(
+        socket_spec,
+        initial_config,
+        pid_file,
+        fallback_agent,
+        bound_tcp,
+        control_socket,
+        log_format,
+        connection_concurrency,
+        reload,
+    ) {
         Ok(()) => Ok(0),
         Err(e) => {
             eprintln!("ERROR: {}", e);
-            Ok(1)
+            Ok(exit_code_for(e.category()))
         }
     }
 }
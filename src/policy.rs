@@ -0,0 +1,155 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-message filtering for `--readonly` and `--sign-only`, which reject mutating agent requests
+//! instead of blindly relaying them the way `proxy::proxy_request` does.
+//!
+//! Unlike `proxy`, this module parses the agent wire format -- a 4-byte big-endian length followed
+//! by a 1-byte message type and its payload -- so that it can decide, frame by frame, whether to
+//! forward a request to the real agent or to answer it locally with a rejection.
+
+use crate::proxy::SetReadTimeout;
+use log::trace;
+use std::io::{Read, Write};
+use std::sync::atomic::AtomicBool;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// `SSH_AGENT_FAILURE`: the standard rejection reply for a request we refuse to forward.
+const SSH_AGENT_FAILURE: u8 = 5;
+
+/// `SSH_AGENTC_REQUEST_IDENTITIES`: list the keys the agent holds.
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+
+/// `SSH_AGENTC_SIGN_REQUEST`: sign a challenge with one of the agent's keys.
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
+/// Maximum size of a single request frame we will buffer before forwarding it.  Real agent
+/// requests are at most a few kilobytes; this bounds how much a misbehaving client can make us
+/// allocate, mirroring `MAX_PROBE_REPLY_LEN` in `find.rs` and `MAX_TOKEN_LEN` in `tcp.rs`.
+const MAX_FRAME_LEN: u32 = 256 * 1024;
+
+/// A pre-canned `SSH_AGENT_FAILURE` reply frame: length 1, type `SSH_AGENT_FAILURE`.
+const FAILURE_REPLY: [u8; 5] = [0, 0, 0, 1, SSH_AGENT_FAILURE];
+
+/// Which requests a connection is allowed to forward to the real agent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Allow listing identities and signing, but reject any request that would add, remove, or
+    /// otherwise mutate the keys held by the agent.
+    ReadOnly,
+
+    /// Like `ReadOnly`, but also rejects listing identities: only signing is allowed.  This is the
+    /// narrowest mode, suitable for forwarding into an environment that should not even learn which
+    /// keys are available.
+    SignOnly,
+}
+
+impl Mode {
+    /// Returns whether a request of the given `msg_type` may be forwarded to the real agent.
+    fn allows(self, msg_type: u8) -> bool {
+        match self {
+            Mode::ReadOnly => {
+                matches!(msg_type, SSH_AGENTC_REQUEST_IDENTITIES | SSH_AGENTC_SIGN_REQUEST)
+            }
+            Mode::SignOnly => msg_type == SSH_AGENTC_SIGN_REQUEST,
+        }
+    }
+}
+
+/// Reads one length-prefixed frame from `stream`, returning its raw bytes including the 4-byte
+/// length prefix, or `None` if the peer closed the connection before sending another frame.
+fn read_frame(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match stream.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(format!("Failed to read frame length: {}", e)),
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(format!("Frame length {} is out of range", len));
+    }
+
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend_from_slice(&len_buf);
+    frame.resize(frame.len() + len as usize, 0);
+    stream
+        .read_exact(&mut frame[4..])
+        .map_err(|e| format!("Failed to read frame body: {}", e))?;
+    Ok(Some(frame))
+}
+
+/// Forwards `client`'s requests to `agent` and `agent`'s responses back to `client`, filtering
+/// each request frame against `mode` first.
+///
+/// A request that `mode` disallows is answered directly with `FAILURE_REPLY` and is never sent to
+/// `agent`; everything else is forwarded and the agent's reply relayed back unchanged.  Unlike
+/// `proxy::proxy_request`, this only tracks one in-flight request at a time, which matches how the
+/// agent protocol is actually used: a client waits for a reply before sending its next request.
+///
+/// `client` is generic so that this same filter serves both the Unix socket and `--listen-tcp`
+/// connections; `agent` is always a `UnixStream` since that is the only kind of real agent socket
+/// we ever connect to.
+///
+/// Returns the number of bytes written to `agent` and to `client`, respectively -- including the
+/// locally-generated `FAILURE_REPLY` bytes in the latter, since those are still bytes the client
+/// received -- for `event::log_connection_closed` to report.
+///
+/// `stop` is checked periodically while waiting for the client's next request (see
+/// `proxy::read_frame_checking_shutdown`), so an idle client under `--readonly`/`--sign-only` cannot
+/// block a worker past a requested shutdown.
+pub(crate) fn proxy_filtered<C: Read + Write + SetReadTimeout>(
+    client: &mut C,
+    agent: &mut std::os::unix::net::UnixStream,
+    mode: Mode,
+    stop: &AtomicBool,
+) -> Result<(u64, u64)> {
+    let mut bytes_to_agent: u64 = 0;
+    let mut bytes_to_client: u64 = 0;
+
+    while let Some(frame) = crate::proxy::read_frame_checking_shutdown(client, stop)? {
+        let msg_type = frame[4];
+
+        if !mode.allows(msg_type) {
+            trace!("Rejecting request type {} under {:?}", msg_type, mode);
+            client
+                .write_all(&FAILURE_REPLY)
+                .map_err(|e| format!("Failed to write rejection to client: {}", e))?;
+            bytes_to_client += FAILURE_REPLY.len() as u64;
+            continue;
+        }
+
+        trace!("Forwarding request type {} under {:?}", msg_type, mode);
+        agent.write_all(&frame).map_err(|e| format!("Failed to forward request to agent: {}", e))?;
+        bytes_to_agent += frame.len() as u64;
+
+        let reply = read_frame(agent)?
+            .ok_or_else(|| "Agent closed the connection before replying".to_owned())?;
+        client.write_all(&reply).map_err(|e| format!("Failed to write reply to client: {}", e))?;
+        bytes_to_client += reply.len() as u64;
+    }
+
+    Ok((bytes_to_agent, bytes_to_client))
+}
@@ -21,198 +21,446 @@
 // WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
 // WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-//! Proxies traffic between two sockets.
+//! Proxies traffic between a client and an agent, understanding the SSH agent wire format instead
+//! of blindly copying bytes.
+//!
+//! Like `policy`, this parses the agent protocol -- a 4-byte big-endian length followed by a
+//! 1-byte message type and its payload -- frame by frame, which is what lets a caller plug in an
+//! allow/deny policy over the forwarded operations instead of only being able to relay or drop an
+//! entire connection. `proxy_request` itself forwards every message type unconditionally; it is
+//! the `None`-policy fallback used once neither `--readonly`/`--sign-only` (see `policy`) nor
+//! `fdpass`'s SCM_RIGHTS handoff apply.
+//!
+//! `proxy_request` also bounds how long a connection may sit idle and how long it may live overall
+//! (`--idle-timeout`/`--total-timeout`), so that a dead SSH session whose local end never closes
+//! its agent socket cleanly does not pin a thread and an agent connection open forever -- the exact
+//! kind of leak this tool otherwise exists to paper over.
 
 use log::trace;
-use std::io::{self, Result};
-use tokio::io::Interest;
-use tokio::net::UnixStream;
-use tokio::select;
-
-/// Default internal read buffer size.  This should be big enough to fit most reasonable agent
-/// messages in one read/write, but the proxying logic can deal with partial messages.
-const READ_BUF_SIZE: usize = 1024;
-
-/// Handles one read from `stream` once the stream is readable.  Uses an internal buffer of
-/// size `read_buf_size` and returns up to this many bytes.
-async fn handle_read(stream: &mut UnixStream, read_buf_size: usize) -> Result<Vec<u8>> {
-    let mut partial = vec![0; read_buf_size];
-    match stream.try_read(&mut partial) {
-        Ok(n) => {
-            partial.truncate(n);
-            Ok(partial)
-        }
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-            // The readiness event is a false positive.
-            partial.truncate(0);
-            Ok(partial)
-        }
-        Err(e) => Err(e),
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// Sockets that can have a read timeout installed, so `proxy_request` can bound how long it waits
+/// for the next frame regardless of whether `client` is a Unix socket or a `--listen-tcp` peer.
+pub(crate) trait SetReadTimeout {
+    /// See `std::os::unix::net::UnixStream::set_read_timeout`/`std::net::TcpStream::set_read_timeout`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl SetReadTimeout for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
     }
 }
 
-/// Handles one write to `stream` of all of `buf` once the stream is writable.
-async fn handle_write(stream: &mut UnixStream, buf: &[u8]) -> Result<()> {
-    let mut pos = 0;
-    while pos < buf.len() {
-        stream.writable().await?;
-        match stream.try_write(&buf[pos..]) {
-            Ok(n) => {
-                pos += n;
-                debug_assert!(pos <= buf.len());
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // The readiness event is a false positive; try again.
-            }
-            Err(e) => return Err(e),
-        }
+impl SetReadTimeout for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
     }
-    Ok(())
 }
 
-/// Forwards all request from the client to the agent and all responses from the agent to the client.
+/// `SSH_AGENTC_REQUEST_IDENTITIES`: list the keys the agent holds.
+#[allow(dead_code)]
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+
+/// `SSH_AGENTC_SIGN_REQUEST`: sign a challenge with one of the agent's keys.
+#[allow(dead_code)]
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
+/// Maximum size of a single request frame we will buffer before forwarding it, mirroring
+/// `MAX_FRAME_LEN` in `policy.rs`.
+const MAX_FRAME_LEN: u32 = 256 * 1024;
+
+/// How often a blocking read wakes up to check whether the process is shutting down, regardless of
+/// whatever `idle_timeout`/`total_timeout` the caller configured (or didn't). Not itself
+/// user-configurable and unrelated to those two: it exists purely so a worker parked reading from an
+/// otherwise-silent client cannot block `run`'s `thread::scope` join past a `SIGTERM` the way an
+/// unbounded blocking read would.
 ///
-/// This is separate from `proxy_request` for testing purposes only as it allows configuring the
-/// internal behavior of the proxying logic.
-async fn proxy_request_internal(
-    client: &mut UnixStream,
-    agent: &mut UnixStream,
-    read_buf_size: usize,
-) -> Result<()> {
-    let mut client_buf = vec![];
-    let mut agent_buf = vec![];
-    let mut client_done = false;
-    let mut agent_done = false;
-    while !(client_done && agent_done && agent_buf.is_empty() && client_buf.is_empty()) {
-        select! {
-            ready = client.ready(Interest::READABLE), if !client_done => {
-                if ready?.is_readable() {
-                    let partial = handle_read(client, read_buf_size).await?;
-                    trace!(
-                        "Read {} bytes from client; client buffer is now {}",
-                        partial.len(), partial.len() + client_buf.len()
-                    );
-                    if partial.is_empty() {
-                        trace!("Client socket is now half-closed");
-                        client_done = true;
-                    } else {
-                        client_buf.extend_from_slice(&partial);
-                    }
-                }
-            }
+/// `pub(crate)` so `fdpass::peer_requests_fd_passing` -- which peeks at a client's first byte before
+/// this module ever gets a chance to -- polls `stop` on the same cadence.
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-            ready = client.ready(Interest::WRITABLE), if !agent_buf.is_empty() => {
-                if ready?.is_writable() {
-                    trace!("Writing {} bytes to client", agent_buf.len());
-                    handle_write(client, &mut agent_buf).await?;
-                    agent_buf.clear();
-                }
+/// Reads one length-prefixed frame from `stream`, returning its raw bytes including the 4-byte
+/// length prefix, or `None` if the peer closed the connection before sending another frame.
+///
+/// Unlike the `read_frame` helpers in `policy`/`aggregate`, this returns an `io::Result`
+/// rather than already having converted the error to a `String`, so that `proxy_request` can tell
+/// a read timeout apart from every other failure and report which deadline fired.
+fn read_frame(stream: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match stream.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame length {} is out of range", len),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend_from_slice(&len_buf);
+    frame.resize(frame.len() + len as usize, 0);
+    stream.read_exact(&mut frame[4..])?;
+    Ok(Some(frame))
+}
+
+/// Returns whether `e` is the error `std::io::Read::read`/`read_exact` reports when a socket's
+/// configured read timeout elapses before any data arrives -- `WouldBlock` on Linux, `TimedOut` on
+/// other platforms -- as opposed to a connection actually failing.
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// Computes the read timeout to install before the next frame read: the smaller of `idle_timeout`
+/// and however much of `total_timeout` remains since `start`, or `None` if neither is configured.
+/// Returns `Err` once `total_timeout` has already elapsed, so the caller never blocks past it.
+fn next_read_timeout(
+    idle_timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+    start: Instant,
+) -> Result<Option<Duration>> {
+    let remaining_total = match total_timeout {
+        Some(total) => {
+            let elapsed = start.elapsed();
+            if elapsed >= total {
+                return Err(format!("Connection exceeded the total timeout of {:?}", total));
             }
+            Some(total - elapsed)
+        }
+        None => None,
+    };
+
+    Ok(match (idle_timeout, remaining_total) {
+        (Some(idle), Some(remaining)) => Some(idle.min(remaining)),
+        (Some(idle), None) => Some(idle),
+        (None, Some(remaining)) => Some(remaining),
+        (None, None) => None,
+    })
+}
+
+/// Reads one frame from `stream`, having first installed whatever read timeout `idle_timeout` and
+/// `total_timeout` call for, and turns a timed-out read into an error naming which of the two
+/// deadlines is responsible.
+///
+/// The timeout actually installed on `stream` is capped at `SHUTDOWN_POLL_INTERVAL` so this wakes up
+/// periodically to check `stop` even when `idle_timeout`/`total_timeout` are both `None` (i.e. the
+/// caller asked for an unbounded connection); a tick that fires only because of that cap, rather than
+/// because the caller's own deadline elapsed, is not itself an error and simply loops around.
+fn read_frame_with_timeout<S: Read + SetReadTimeout>(
+    stream: &mut S,
+    idle_timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+    start: Instant,
+    stop: &AtomicBool,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        let remaining = next_read_timeout(idle_timeout, total_timeout, start)?;
+        let wait = remaining.map(|d| d.min(SHUTDOWN_POLL_INTERVAL)).unwrap_or(SHUTDOWN_POLL_INTERVAL);
+        stream.set_read_timeout(Some(wait)).map_err(|e| format!("Failed to set read timeout: {}", e))?;
 
-            ready = agent.ready(Interest::READABLE), if !agent_done => {
-                if ready?.is_readable() {
-                    let partial = handle_read(agent, read_buf_size).await?;
-                    trace!(
-                        "Read {} bytes from agent; agent buffer is now {}",
-                        partial.len(), partial.len() + agent_buf.len()
-                    );
-                    if partial.is_empty() {
-                        trace!("Agent socket is now half-closed");
-                        agent_done = true;
-                    } else {
-                        agent_buf.extend_from_slice(&partial);
-                    }
+        match read_frame(stream) {
+            Ok(frame) => return Ok(frame),
+            Err(e) if is_timeout(&e) => {
+                if stop.load(Ordering::Relaxed) {
+                    return Err("Shutting down".to_owned());
+                }
+                if !remaining.is_some_and(|d| wait >= d) {
+                    // This tick only fired because of `SHUTDOWN_POLL_INTERVAL`, not because the
+                    // caller's own idle/total deadline elapsed; keep waiting for it.
+                    continue;
+                }
+                if next_read_timeout(idle_timeout, total_timeout, start).is_err() {
+                    return Err(format!(
+                        "Connection exceeded the total timeout of {:?}",
+                        total_timeout.unwrap()
+                    ));
                 }
+                return Err(format!(
+                    "Connection idle for longer than the idle timeout of {:?}",
+                    idle_timeout.unwrap()
+                ));
             }
+            Err(e) => return Err(format!("Failed to read frame: {}", e)),
+        }
+    }
+}
 
-            ready = agent.ready(Interest::WRITABLE), if !client_buf.is_empty() => {
-                if ready?.is_writable() {
-                    trace!("Writing {} bytes to agent", client_buf.len());
-                    handle_write(agent, &mut client_buf).await?;
-                    client_buf.clear();
+/// Fills `buf` from `stream`, waking up every `SHUTDOWN_POLL_INTERVAL` to check `stop` instead of
+/// blocking indefinitely.
+///
+/// Loops on plain `Read::read` rather than `read_exact`, so that bytes already delivered before a
+/// poll tick's timeout fires are kept rather than thrown away on the next attempt -- `read_exact`
+/// gives no way to resume a read after a partial failure, which would otherwise risk corrupting a
+/// frame split across more than one tick.
+///
+/// Returns `Ok(false)` if the peer closed before contributing a single byte to `buf` -- the same
+/// "no more frames" signal a plain `read_exact`'s `UnexpectedEof` conveys elsewhere in this crate.
+fn read_fully_checking_shutdown<S: Read + SetReadTimeout>(
+    stream: &mut S,
+    buf: &mut [u8],
+    stop: &AtomicBool,
+) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        stream
+            .set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))
+            .map_err(|e| format!("Failed to set read timeout: {}", e))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err("Connection closed mid-frame".to_owned()),
+            Ok(n) => filled += n,
+            Err(e) if is_timeout(&e) => {
+                if stop.load(Ordering::Relaxed) {
+                    return Err("Shutting down".to_owned());
                 }
             }
+            Err(e) => return Err(format!("Failed to read from stream: {}", e)),
         }
     }
+    Ok(true)
+}
 
-    Ok(())
+/// Reads one length-prefixed frame from `stream` the same way `read_frame` does, but periodically
+/// checks `stop` while waiting for it instead of blocking forever.
+///
+/// `policy::proxy_filtered`, `aggregate::proxy_aggregated`, and `failover::proxy_with_failover` all
+/// call this for their *client*-facing read -- the one that blocks for however long a client chooses
+/// to idle, with no idle/total timeout of its own to fall back on -- so a client sitting idle under
+/// `--readonly`/`--sign-only`, `--aggregate`, or `--failover` cannot wedge a worker past a `SIGTERM`.
+pub(crate) fn read_frame_checking_shutdown<S: Read + SetReadTimeout>(
+    stream: &mut S,
+    stop: &AtomicBool,
+) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    if !read_fully_checking_shutdown(stream, &mut len_buf, stop)? {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(format!("Frame length {} is out of range", len));
+    }
+
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend_from_slice(&len_buf);
+    frame.resize(frame.len() + len as usize, 0);
+    if !read_fully_checking_shutdown(stream, &mut frame[4..], stop)? {
+        return Err("Connection closed mid-frame".to_owned());
+    }
+    Ok(Some(frame))
 }
 
-/// Forwards all request from the client to the agent and all responses from the agent to the client.
-pub(crate) async fn proxy_request(client: &mut UnixStream, agent: &mut UnixStream) -> Result<()> {
-    //proxy_request_internal(client, agent, READ_BUF_SIZE).await
-    tokio::io::copy_bidirectional(client, agent).await?;
-    Ok(())
+/// Everything `proxy_request` reports about how a connection went, for
+/// `event::log_connection_closed` to include in its summary.
+///
+/// There is no separate "did it end cleanly" flag, same as in `failover::FailoverResult`: this loop
+/// only ever returns `Ok` when the client closed its end between a reply and the next request, so
+/// reaching `Ok` at all *is* the clean-EOF signal: any other termination -- the agent hanging up
+/// mid-reply, an idle or total timeout firing -- surfaces as `Err` instead.
+pub(crate) struct ProxyResult {
+    /// Bytes forwarded from the client to the agent, across every request.
+    pub(crate) bytes_to_agent: u64,
+
+    /// Bytes forwarded from the agent to the client, across every reply.
+    pub(crate) bytes_to_client: u64,
+}
+
+/// Forwards all requests from the client to the agent and all responses from the agent to the
+/// client, one message at a time, returning the number of bytes copied in each direction for
+/// `event::log_connection_closed` to report.
+///
+/// This forwards every request type unconditionally; `policy::proxy_filtered` is the variant that
+/// rejects requests a `--readonly`/`--sign-only` policy disallows by message type. Both walk the
+/// same frame-at-a-time protocol, so a policy that needs finer-grained allow/deny rules than
+/// `policy::Mode` belongs there, next to its existing message-type constants, rather than in this
+/// unconditional relay.
+///
+/// `client` is generic so that this same relay serves both the Unix socket and `--listen-tcp`
+/// connections; `agent` is always a `UnixStream` since that is the only kind of real agent socket
+/// we ever connect to.
+///
+/// `idle_timeout` aborts the connection once neither side has made progress for that long;
+/// `total_timeout` aborts it once it has been open for that long regardless of activity. Either
+/// may be `None` to disable that particular cap; both reset their accounting from every call, so a
+/// `reselect`-style caller (see `failover::proxy_with_failover`) that hands `proxy_request` a fresh
+/// connection gets a fresh budget.
+///
+/// `stop` is checked every `SHUTDOWN_POLL_INTERVAL` regardless of `idle_timeout`/`total_timeout`, so
+/// a connection left open with neither cap configured still unblocks promptly once `run` asks every
+/// worker to shut down.
+pub(crate) fn proxy_request<C: Read + Write + SetReadTimeout>(
+    client: &mut C,
+    agent: &mut std::os::unix::net::UnixStream,
+    idle_timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+    stop: &AtomicBool,
+) -> Result<ProxyResult> {
+    let start = Instant::now();
+    let mut bytes_to_agent: u64 = 0;
+    let mut bytes_to_client: u64 = 0;
+
+    while let Some(frame) = read_frame_with_timeout(client, idle_timeout, total_timeout, start, stop)? {
+        trace!("Forwarding request type {}", frame[4]);
+        agent.write_all(&frame).map_err(|e| format!("Failed to forward request to agent: {}", e))?;
+        bytes_to_agent += frame.len() as u64;
+
+        let reply = read_frame_with_timeout(agent, idle_timeout, total_timeout, start, stop)?
+            .ok_or_else(|| "Agent closed the connection before replying".to_owned())?;
+        client.write_all(&reply).map_err(|e| format!("Failed to write reply to client: {}", e))?;
+        bytes_to_client += reply.len() as u64;
+    }
+
+    Ok(ProxyResult { bytes_to_agent, bytes_to_client })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::net::UnixStream;
 
-    /// Reads one message from `stream` in one go.
-    async fn read_all(stream: &mut UnixStream, expected_len: usize) -> io::Result<Vec<u8>> {
-        let mut buf = [0; 1024]; // Should be big enough for all test messages.
-        let mut n = 0;
-        while n < expected_len {
-            stream.readable().await?;
-            match stream.try_read(&mut buf[n..]) {
-                Ok(n2) => n += n2,
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
-                Err(e) => return Err(e),
-            }
-        }
-        assert!(n < buf.len(), "Message reached buffer size; might be incomplete");
-        Ok(buf[0..n].to_owned())
-    }
-
-    /// Writes all of `message` into `stream` in one go.
-    async fn write_all(stream: &mut UnixStream, message: &[u8]) -> io::Result<()> {
-        stream.writable().await?;
-        let n = stream.try_write(message)?;
-        assert_eq!(n, message.len(), "Failed to write message in one go");
-        Ok(())
-    }
-
-    /// Performs a bidirectional proxying test with an internal read size of `read_buf_size`
-    /// by sending `client_msg` to the agent and responding with `agent_msg` to the client.
-    async fn do_bidi_test(
-        read_buf_size: usize,
-        client_msg: &str,
-        agent_msg: &str,
-    ) -> io::Result<()> {
-        let (mut client_1, mut client_2) = UnixStream::pair()?;
-        let (mut agent_1, mut agent_2) = UnixStream::pair()?;
-
-        let proxy = tokio::spawn(async move {
-            proxy_request_internal(&mut client_2, &mut agent_1, read_buf_size).await
+    /// `SSH_AGENT_SUCCESS`, used as a stand-in reply type in these tests; the contents of a reply
+    /// frame are opaque to `proxy_request`.
+    const SSH_AGENT_SUCCESS: u8 = 6;
+
+    /// Performs one proxying round-trip through `proxy_request`, sending `client_msg` as a single
+    /// framed request and responding with `agent_msg` as a single framed reply, and asserts the
+    /// byte counts it returns.
+    fn do_roundtrip_test(client_msg: &[u8], agent_msg: &[u8]) {
+        let (mut client_near, mut client_far) = UnixStream::pair().expect("Failed to create socketpair");
+        let (mut agent_near, mut agent_far) = UnixStream::pair().expect("Failed to create socketpair");
+
+        let mut request = (client_msg.len() as u32 + 1).to_be_bytes().to_vec();
+        request.push(SSH_AGENTC_REQUEST_IDENTITIES);
+        request.extend_from_slice(client_msg);
+
+        let mut reply = (agent_msg.len() as u32 + 1).to_be_bytes().to_vec();
+        reply.push(SSH_AGENT_SUCCESS);
+        reply.extend_from_slice(agent_msg);
+
+        let expected_to_agent = request.len() as u64;
+        let expected_to_client = reply.len() as u64;
+
+        let reply_clone = reply.clone();
+        let agent_thread = std::thread::spawn(move || {
+            let mut got = vec![0; request.len()];
+            agent_far.read_exact(&mut got).expect("Failed to read forwarded request");
+            assert_eq!(got, request, "Agent did not receive the request unchanged");
+            agent_far.write_all(&reply_clone).expect("Failed to write agent reply");
         });
 
-        let client_msg = client_msg.as_bytes();
-        write_all(&mut client_1, client_msg).await?;
-        assert_eq!(client_msg, read_all(&mut agent_2, client_msg.len()).await?);
+        client_near.write_all(&request).expect("Failed to write client request");
+        client_near.shutdown(std::net::Shutdown::Write).expect("Failed to shut down write side");
+
+        let stats = proxy_request(&mut client_far, &mut agent_near, None, None, &AtomicBool::new(false))
+            .expect("proxy_request failed");
+        assert_eq!(stats.bytes_to_agent, expected_to_agent);
+        assert_eq!(stats.bytes_to_client, expected_to_client);
+
+        let mut got_reply = vec![0; reply.len()];
+        client_near.read_exact(&mut got_reply).expect("Failed to read reply at client");
+        assert_eq!(got_reply, reply);
+
+        agent_thread.join().expect("Agent thread panicked");
+    }
 
-        let agent_msg = agent_msg.as_bytes();
-        write_all(&mut agent_2, agent_msg).await?;
-        assert_eq!(agent_msg, read_all(&mut client_1, agent_msg.len()).await?);
+    #[test]
+    fn test_small_message() {
+        do_roundtrip_test(b"hello", b"world");
+    }
 
-        drop(client_1);
-        proxy.await??;
-        Ok(())
+    #[test]
+    fn test_empty_payload() {
+        do_roundtrip_test(b"", b"");
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn test_one_byte_at_a_time() -> io::Result<()> {
-        do_bidi_test(1, "abcdefg", "hijklmn").await
+    #[test]
+    fn test_larger_message() {
+        let client_msg = vec![b'c'; 4096];
+        let agent_msg = vec![b'a'; 8192];
+        do_roundtrip_test(&client_msg, &agent_msg);
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn test_chunked() -> io::Result<()> {
-        do_bidi_test(8, "request longer than eight bytes", "response longer than eight bytes").await
+    #[test]
+    fn test_idle_timeout_fires_with_no_traffic() {
+        let (_client_near, mut client_far) = UnixStream::pair().expect("Failed to create socketpair");
+        let (_agent_near, mut agent_near) = UnixStream::pair().expect("Failed to create socketpair");
+
+        let err = proxy_request(
+            &mut client_far,
+            &mut agent_near,
+            Some(Duration::from_millis(50)),
+            None,
+            &AtomicBool::new(false),
+        )
+        .expect_err("proxy_request should have timed out");
+        assert!(err.contains("idle"), "Expected an idle timeout error, got: {}", err);
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn test_one_chunk() -> io::Result<()> {
-        do_bidi_test(1024, "request shorter than 1024 bytes", "response shorter than 1024 bytes")
-            .await
+    #[test]
+    fn test_total_timeout_fires_even_with_traffic() {
+        let (mut client_near, mut client_far) = UnixStream::pair().expect("Failed to create socketpair");
+        let (mut agent_near, mut agent_far) = UnixStream::pair().expect("Failed to create socketpair");
+
+        let total_timeout = Duration::from_millis(100);
+        let keep_alive = std::thread::spawn(move || {
+            // Send one request partway through the total timeout, so the connection sees traffic
+            // and no idle timeout is configured; only the total cap can end it.
+            std::thread::sleep(Duration::from_millis(20));
+            let mut request = 1u32.to_be_bytes().to_vec();
+            request.push(SSH_AGENTC_REQUEST_IDENTITIES);
+            let _ = client_near.write_all(&request);
+
+            let mut got = vec![0; request.len()];
+            if agent_far.read_exact(&mut got).is_ok() {
+                let mut reply = 1u32.to_be_bytes().to_vec();
+                reply.push(SSH_AGENT_SUCCESS);
+                let _ = agent_far.write_all(&reply);
+            }
+
+            let mut discard = [0; 16];
+            let _ = client_near.read(&mut discard);
+        });
+
+        let err = proxy_request(
+            &mut client_far,
+            &mut agent_near,
+            None,
+            Some(total_timeout),
+            &AtomicBool::new(false),
+        )
+        .expect_err("proxy_request should have hit the total timeout");
+        assert!(err.contains("total"), "Expected a total timeout error, got: {}", err);
+
+        keep_alive.join().expect("Keep-alive thread panicked");
+    }
+
+    #[test]
+    fn test_stop_flag_unblocks_connection_with_no_timeouts_configured() {
+        let (_client_near, mut client_far) = UnixStream::pair().expect("Failed to create socketpair");
+        let (_agent_near, mut agent_near) = UnixStream::pair().expect("Failed to create socketpair");
+
+        let stop = AtomicBool::new(false);
+        let before = Instant::now();
+        let err = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                stop.store(true, Ordering::Relaxed);
+            });
+            proxy_request(&mut client_far, &mut agent_near, None, None, &stop)
+                .expect_err("proxy_request should have noticed the stop flag")
+        });
+        assert!(err.contains("Shutting down"), "Expected a shutdown error, got: {}", err);
+        assert!(
+            before.elapsed() < SHUTDOWN_POLL_INTERVAL * 2,
+            "proxy_request took too long to notice the stop flag"
+        );
     }
 }
@@ -0,0 +1,239 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Optional PROXY protocol v2 header parsing for `--listen`/`--listen-tcp` endpoints sitting behind
+//! a TCP forwarder (socat, haproxy, `ssh -L`, ...), so the forwarder's idea of the real client
+//! address survives the hop instead of every connection appearing to come from the forwarder
+//! itself.
+//!
+//! Only the binary v2 header is understood (the older text-based v1 header some forwarders can
+//! still emit is not); see <https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt>. The header
+//! is always read and discarded before the first agent message is parsed, since `proxy::read_frame`
+//! and friends would otherwise mistake its bytes for the start of a length-prefixed frame.
+
+use std::io::Read;
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// The fixed 12-byte sequence that opens every v2 header, chosen by the spec to never collide with
+/// a plausible v1 text header or arbitrary client data.
+const SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x51, 0x0A];
+
+/// Size of the header's fixed part: the 12-byte signature, one version/command byte, one
+/// address-family/transport byte, and the 2-byte big-endian length of the address block that
+/// follows. The address block itself is variable-length and read separately.
+const FIXED_HEADER_LEN: usize = 16;
+
+/// High nibble of the version/command byte for protocol version 2; any other version is a header
+/// we don't know how to parse.
+const VERSION_2: u8 = 0x2;
+
+/// Low nibble of the version/command byte for the `LOCAL` command, used by health checks and the
+/// like: the connection did not forward a real client, so its address block (if any) is discarded.
+const COMMAND_LOCAL: u8 = 0x0;
+
+/// High nibble of the address-family/transport byte for `AF_INET`.
+const FAMILY_INET: u8 = 0x1;
+
+/// High nibble of the address-family/transport byte for `AF_INET6`.
+const FAMILY_INET6: u8 = 0x2;
+
+/// Size of the IPv4 address block: 4-byte source address, 4-byte destination address, 2-byte source
+/// port, 2-byte destination port.
+const ADDR_LEN_INET: usize = 4 + 4 + 2 + 2;
+
+/// Size of the IPv6 address block: 16-byte source address, 16-byte destination address, 2-byte
+/// source port, 2-byte destination port.
+const ADDR_LEN_INET6: usize = 16 + 16 + 2 + 2;
+
+/// What a v2 header told us about the connection it precedes.
+pub(crate) struct ProxyProtocolHeader {
+    /// The forwarder's claimed `ip:port` for the original client, or `None` if the header was a
+    /// `LOCAL` command (no real client to report) or named an address family/transport this parser
+    /// does not decode (e.g. `AF_UNIX`).
+    pub(crate) source_addr: Option<String>,
+}
+
+/// Reads and validates one PROXY protocol v2 header off the front of `stream`, consuming exactly
+/// its bytes -- the fixed part plus the declared address block -- and leaving the first real agent
+/// message as the next thing to read.
+///
+/// `std::io::Read::read_exact` already retries until the requested number of bytes has arrived or
+/// the peer hangs up, so a header split across several TCP segments is handled without any extra
+/// buffering here.
+pub(crate) fn read_header(stream: &mut impl Read) -> Result<ProxyProtocolHeader> {
+    let mut fixed = [0; FIXED_HEADER_LEN];
+    stream.read_exact(&mut fixed).map_err(|e| format!("Failed to read PROXY protocol header: {}", e))?;
+
+    if fixed[..SIGNATURE.len()] != SIGNATURE {
+        return Err("Connection did not start with a PROXY protocol v2 signature".to_owned());
+    }
+
+    let version = fixed[12] >> 4;
+    if version != VERSION_2 {
+        return Err(format!("Unsupported PROXY protocol version {}", version));
+    }
+    let command = fixed[12] & 0x0F;
+
+    let family = fixed[13] >> 4;
+
+    let addr_len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let mut addr_block = vec![0; addr_len];
+    stream
+        .read_exact(&mut addr_block)
+        .map_err(|e| format!("Failed to read PROXY protocol address block: {}", e))?;
+
+    if command == COMMAND_LOCAL {
+        return Ok(ProxyProtocolHeader { source_addr: None });
+    }
+
+    Ok(ProxyProtocolHeader { source_addr: parse_source_addr(family, &addr_block) })
+}
+
+/// Extracts the source `ip:port` out of a v2 address block, given the family it was declared to
+/// hold; returns `None` for any family this parser doesn't decode (e.g. `AF_UNIX`) or a block too
+/// short for the family it claims.
+fn parse_source_addr(family: u8, block: &[u8]) -> Option<String> {
+    match family {
+        FAMILY_INET if block.len() >= ADDR_LEN_INET => {
+            let ip = std::net::Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Some(format!("{}:{}", ip, port))
+        }
+        FAMILY_INET6 if block.len() >= ADDR_LEN_INET6 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&block[0..16]);
+            let ip = std::net::Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Some(format!("[{}]:{}", ip, port))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a well-formed v2 header carrying `addr_block` as its address block, with `command`
+    /// and `family` in the appropriate nibbles.
+    fn build_header(command: u8, family: u8, addr_block: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push((VERSION_2 << 4) | command);
+        header.push(family << 4);
+        header.extend_from_slice(&(addr_block.len() as u16).to_be_bytes());
+        header.extend_from_slice(addr_block);
+        header
+    }
+
+    #[test]
+    fn test_ipv4_proxy_command() {
+        let mut addr_block = Vec::new();
+        addr_block.extend_from_slice(&[10, 0, 0, 1]);
+        addr_block.extend_from_slice(&[10, 0, 0, 2]);
+        addr_block.extend_from_slice(&22334u16.to_be_bytes());
+        addr_block.extend_from_slice(&22u16.to_be_bytes());
+        let mut data = build_header(0x1, FAMILY_INET, &addr_block);
+        data.extend_from_slice(b"trailing agent bytes");
+
+        let mut cursor = std::io::Cursor::new(data);
+        let header = read_header(&mut cursor).expect("Failed to parse header");
+        assert_eq!(header.source_addr.as_deref(), Some("10.0.0.1:22334"));
+
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).expect("Failed to read trailing bytes");
+        assert_eq!(rest, b"trailing agent bytes");
+    }
+
+    #[test]
+    fn test_ipv6_proxy_command() {
+        let mut addr_block = Vec::new();
+        addr_block.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        addr_block.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        addr_block.extend_from_slice(&4433u16.to_be_bytes());
+        addr_block.extend_from_slice(&22u16.to_be_bytes());
+        let data = build_header(0x1, FAMILY_INET6, &addr_block);
+
+        let mut cursor = std::io::Cursor::new(data);
+        let header = read_header(&mut cursor).expect("Failed to parse header");
+        assert_eq!(header.source_addr.as_deref(), Some("[::1]:4433"));
+    }
+
+    #[test]
+    fn test_local_command_has_no_source_addr() {
+        let data = build_header(COMMAND_LOCAL, FAMILY_INET, &[0; ADDR_LEN_INET]);
+
+        let mut cursor = std::io::Cursor::new(data);
+        let header = read_header(&mut cursor).expect("Failed to parse header");
+        assert_eq!(header.source_addr, None);
+    }
+
+    #[test]
+    fn test_header_split_across_reads() {
+        let mut addr_block = Vec::new();
+        addr_block.extend_from_slice(&[127, 0, 0, 1]);
+        addr_block.extend_from_slice(&[127, 0, 0, 1]);
+        addr_block.extend_from_slice(&12345u16.to_be_bytes());
+        addr_block.extend_from_slice(&22u16.to_be_bytes());
+        let data = build_header(0x1, FAMILY_INET, &addr_block);
+
+        /// A `Read` that only ever hands back one byte at a time, to exercise the `read_exact`
+        /// retry path the way a header trickling in over several TCP segments would.
+        struct OneByteAtATime(std::io::Cursor<Vec<u8>>);
+        impl Read for OneByteAtATime {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(&mut buf[..1.min(buf.len())])
+            }
+        }
+
+        let mut reader = OneByteAtATime(std::io::Cursor::new(data));
+        let header = read_header(&mut reader).expect("Failed to parse header");
+        assert_eq!(header.source_addr.as_deref(), Some("127.0.0.1:12345"));
+    }
+
+    #[test]
+    fn test_rejects_bad_signature() {
+        let mut data = SIGNATURE.to_vec();
+        data[0] = 0xFF;
+        data.push((VERSION_2 << 4) | 0x1);
+        data.push(FAMILY_INET << 4);
+        data.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut cursor = std::io::Cursor::new(data);
+        let err = read_header(&mut cursor).expect_err("Bad signature should be rejected");
+        assert!(err.contains("signature"), "Expected a signature error, got: {}", err);
+    }
+
+    #[test]
+    fn test_rejects_unsupported_version() {
+        let mut data = SIGNATURE.to_vec();
+        data.push((0x1 << 4) | 0x1);
+        data.push(FAMILY_INET << 4);
+        data.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut cursor = std::io::Cursor::new(data);
+        let err = read_header(&mut cursor).expect_err("Version 1 should be rejected");
+        assert!(err.contains("version"), "Expected a version error, got: {}", err);
+    }
+}
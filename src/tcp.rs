@@ -0,0 +1,142 @@
+// Copyright 2025 Julio Merino.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification, are permitted
+// provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this list of conditions
+//   and the following disclaimer.
+// * Redistributions in binary form must reproduce the above copyright notice, this list of
+//   conditions and the following disclaimer in the documentation and/or other materials provided with
+//   the distribution.
+// * Neither the name of ssh-agent-switcher nor the names of its contributors may be used to endorse
+//   or promote products derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR
+// IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+// FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+// WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY
+// WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Optional TCP listener, gated behind a pre-shared token, for forwarding the agent off-host.
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Result type for this module.
+type Result<T> = std::result::Result<T, String>;
+
+/// Start of the IANA ephemeral port range to try when no explicit port is requested.
+const EPHEMERAL_RANGE_START: u16 = 49152;
+
+/// End (inclusive) of the IANA ephemeral port range.
+const EPHEMERAL_RANGE_END: u16 = 65535;
+
+/// Maximum size of an authentication token this listener accepts.  This bounds the read before we
+/// know whether the token matches, to avoid a misbehaving peer making us allocate an unbounded
+/// buffer; same purpose as `MAX_PROBE_REPLY_LEN` in `find.rs`.
+const MAX_TOKEN_LEN: u32 = 4096;
+
+/// Where to bind the optional TCP listener, before the pre-shared token has been read.
+pub struct TcpConfig {
+    /// Host (or IP) to bind to.
+    pub host: String,
+
+    /// Explicit port to bind to; if `None`, a free ephemeral port is chosen automatically.
+    pub port: Option<u16>,
+}
+
+/// A TCP listener that has already been bound, together with the port it ended up on and the
+/// pre-shared token clients must present before any proxying begins.
+pub struct BoundTcp {
+    pub listener: TcpListener,
+    pub port: u16,
+    pub token: Vec<u8>,
+}
+
+/// Reads the pre-shared token out of `path`, trimming a single trailing newline if present (so
+/// that a token file created with a plain text editor or `echo` works as expected).
+pub fn read_token_file(path: &Path) -> Result<Vec<u8>> {
+    let mut contents =
+        std::fs::read(path).map_err(|e| format!("Cannot read token file {}: {}", path.display(), e))?;
+    if contents.last() == Some(&b'\n') {
+        contents.pop();
+    }
+    if contents.is_empty() {
+        return Err(format!("Token file {} is empty", path.display()));
+    }
+    Ok(contents)
+}
+
+/// Binds `config`'s host, and pairs the resulting listener with `token`.
+///
+/// If `config.port` is `None`, this tries binds starting from a pseudo-random offset within the
+/// IANA ephemeral port range (49152-65535) and walks upward, wrapping around once, until one
+/// succeeds.
+pub fn bind(config: &TcpConfig, token: Vec<u8>) -> Result<BoundTcp> {
+    if let Some(port) = config.port {
+        let listener = TcpListener::bind((config.host.as_str(), port))
+            .map_err(|e| format!("Cannot listen on {}:{}: {}", config.host, port, e))?;
+        return Ok(BoundTcp { listener, port, token });
+    }
+
+    let range_len = u32::from(EPHEMERAL_RANGE_END - EPHEMERAL_RANGE_START) + 1;
+    let start_offset = random_offset(range_len);
+    for i in 0..range_len {
+        let port = EPHEMERAL_RANGE_START + ((start_offset + i) % range_len) as u16;
+        match TcpListener::bind((config.host.as_str(), port)) {
+            Ok(listener) => return Ok(BoundTcp { listener, port, token }),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(format!("Cannot listen on {}:{}: {}", config.host, port, e)),
+        }
+    }
+
+    Err(format!(
+        "Could not find a free port in {}-{} on {}",
+        EPHEMERAL_RANGE_START, EPHEMERAL_RANGE_END, config.host
+    ))
+}
+
+/// Returns a pseudo-random starting offset in `0..range_len`, seeded from the current time and our
+/// own pid so that instances started around the same time don't all try the same port first.
+fn random_offset(range_len: u32) -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let seed = now ^ (unsafe { libc::getpid() } as u64);
+    (seed % u64::from(range_len)) as u32
+}
+
+/// Reads a length-prefixed token from `stream` and returns whether it matches `expected`.
+///
+/// The comparison itself runs in time that does not depend on where (or whether) the two buffers
+/// first differ, to avoid leaking the secret token through a timing side channel.
+pub fn authenticate(stream: &mut TcpStream, expected: &[u8]) -> Result<bool> {
+    let mut len_buf = [0; 4];
+    stream.read_exact(&mut len_buf).map_err(|e| format!("Failed to read token length: {}", e))?;
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_TOKEN_LEN {
+        return Err(format!("Token length {} is out of range", len));
+    }
+
+    let mut token = vec![0; len as usize];
+    stream.read_exact(&mut token).map_err(|e| format!("Failed to read token: {}", e))?;
+
+    Ok(constant_time_eq(&token, expected))
+}
+
+/// Compares `a` and `b` for equality without short-circuiting on the first mismatching byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
@@ -45,12 +45,56 @@ struct BackendTask {
 }
 
 /// Backend behavior type.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 enum BackendType {
     /// Echoes back exactly what it receives.
     Echo,
     /// Returns 'a' for every byte received.
     AlwaysA,
+    /// Speaks the framed SSH agent protocol, holding a single fixed identity: answers
+    /// `SSH_AGENTC_REQUEST_IDENTITIES` with that one key, and `SSH_AGENTC_SIGN_REQUEST` with a
+    /// canned signature if (and only if) the request names this backend's key blob.  Used to
+    /// exercise `--aggregate`, which needs backends that actually understand the wire format
+    /// instead of the `Echo`/`AlwaysA` backends' blind byte-piping.
+    Agent { key_blob: Vec<u8>, comment: Vec<u8> },
+}
+
+/// `SSH_AGENT_FAILURE`.
+const SSH_AGENT_FAILURE: u8 = 5;
+/// `SSH_AGENTC_REQUEST_IDENTITIES`.
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+/// `SSH_AGENT_IDENTITIES_ANSWER`.
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+/// `SSH_AGENTC_SIGN_REQUEST`.
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+/// `SSH_AGENT_SIGN_RESPONSE`.
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+/// Reads one length-prefixed frame (the 4-byte length followed by that many bytes, which includes
+/// the message type byte) from `stream`, or `None` on a clean EOF before a new frame starts.
+fn read_frame(stream: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 4];
+    match stream.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Writes one length-prefixed frame to `stream`, given its payload (type byte plus body).
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)
+}
+
+/// Appends a `u32`-length-prefixed string to `out`.
+fn push_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s);
 }
 
 /// Spawn a backend server on a Unix socket.
@@ -81,6 +125,11 @@ fn spawn_backend(socket_path: &Path, backend_type: BackendType) -> BackendTask {
 }
 
 fn handle_backend_connection(mut stream: UnixStream, backend_type: BackendType) {
+    if let BackendType::Agent { key_blob, comment } = backend_type {
+        handle_agent_backend_connection(stream, &key_blob, &comment);
+        return;
+    }
+
     let mut buf = [0u8; 1024];
 
     loop {
@@ -90,6 +139,7 @@ fn handle_backend_connection(mut stream: UnixStream, backend_type: BackendType)
                 let response: Vec<u8> = match backend_type {
                     BackendType::Echo => buf[..n].to_vec(),
                     BackendType::AlwaysA => vec![b'a'; n],
+                    BackendType::Agent { .. } => unreachable!(),
                 };
                 if stream.write_all(&response).is_err() {
                     break;
@@ -100,6 +150,45 @@ fn handle_backend_connection(mut stream: UnixStream, backend_type: BackendType)
     }
 }
 
+/// Serves one connection for a `BackendType::Agent` backend; see there.
+fn handle_agent_backend_connection(mut stream: UnixStream, key_blob: &[u8], comment: &[u8]) {
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(Some(frame)) => frame,
+            Ok(None) | Err(_) => break,
+        };
+        let msg_type = frame[0];
+
+        let reply = match msg_type {
+            SSH_AGENTC_REQUEST_IDENTITIES => {
+                let mut payload = vec![SSH_AGENT_IDENTITIES_ANSWER];
+                payload.extend_from_slice(&1u32.to_be_bytes());
+                push_string(&mut payload, key_blob);
+                push_string(&mut payload, comment);
+                payload
+            }
+            SSH_AGENTC_SIGN_REQUEST => {
+                let req_len = u32::from_be_bytes(frame[1..5].try_into().unwrap()) as usize;
+                let req_blob = &frame[5..5 + req_len];
+                if req_blob == key_blob {
+                    let mut payload = vec![SSH_AGENT_SIGN_RESPONSE];
+                    let mut signature = b"sig-for-".to_vec();
+                    signature.extend_from_slice(comment);
+                    push_string(&mut payload, &signature);
+                    payload
+                } else {
+                    vec![SSH_AGENT_FAILURE]
+                }
+            }
+            _ => vec![SSH_AGENT_FAILURE],
+        };
+
+        if write_frame(&mut stream, &reply).is_err() {
+            break;
+        }
+    }
+}
+
 impl Drop for BackendTask {
     fn drop(&mut self) {
         self.shutdown.store(true, Ordering::SeqCst);
@@ -128,7 +217,7 @@ impl Backend {
         if self.task.is_some() {
             return;
         }
-        self.task = Some(spawn_backend(&self.socket_path, self.backend_type));
+        self.task = Some(spawn_backend(&self.socket_path, self.backend_type.clone()));
     }
 
     fn stop(&mut self) {
@@ -549,3 +638,185 @@ fn test_communication_patterns() {
     }
     child.wait().expect("Failed to wait for child");
 }
+
+/// Test that `--aggregate` merges identities from multiple live backends and routes sign requests
+/// to whichever one actually holds the requested key.
+#[test]
+fn test_aggregate_mode() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+
+    let first_dir = temp_dir.path().join("ssh-first");
+    std::fs::create_dir(&first_dir).expect("Failed to create first subdir");
+    let first_socket = first_dir.join("agent.first");
+    let first_key = b"key-blob-first".to_vec();
+    let first_comment = b"first@example.com".to_vec();
+    let _first_backend = spawn_backend(
+        &first_socket,
+        BackendType::Agent { key_blob: first_key.clone(), comment: first_comment },
+    );
+
+    let second_dir = temp_dir.path().join("ssh-second");
+    std::fs::create_dir(&second_dir).expect("Failed to create second subdir");
+    let second_socket = second_dir.join("agent.second");
+    let second_key = b"key-blob-second".to_vec();
+    let second_comment = b"second@example.com".to_vec();
+    let _second_backend = spawn_backend(
+        &second_socket,
+        BackendType::Agent { key_blob: second_key.clone(), comment: second_comment },
+    );
+
+    let switcher_socket = temp_dir.path().join("switcher.sock");
+
+    let mut child = Command::new(binary_path())
+        .arg("--socket-path")
+        .arg(&switcher_socket)
+        .arg("--agents-dirs")
+        .arg(temp_dir.path())
+        .arg("--aggregate")
+        .spawn()
+        .expect("Failed to start ssh-agent-switcher");
+
+    assert!(
+        wait_for_path(&switcher_socket, Duration::from_secs(5)),
+        "Switcher socket was not created"
+    );
+    assert!(
+        wait_for_path(&first_socket, Duration::from_secs(2))
+            && wait_for_path(&second_socket, Duration::from_secs(2)),
+        "Backend sockets were not created"
+    );
+
+    let mut stream = UnixStream::connect(&switcher_socket).expect("Failed to connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // List identities: expect the union of both backends' keys.
+    write_frame(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]).expect("Failed to send list request");
+    let answer = read_frame(&mut stream)
+        .expect("Failed to read identities answer")
+        .expect("Connection closed before identities answer");
+    assert_eq!(answer[0], SSH_AGENT_IDENTITIES_ANSWER, "Expected an identities answer");
+    let count = u32::from_be_bytes(answer[1..5].try_into().unwrap());
+    assert_eq!(count, 2, "Expected identities from both backends");
+
+    let mut pos = 5;
+    let mut blobs = vec![];
+    for _ in 0..count {
+        let blob_len = u32::from_be_bytes(answer[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        blobs.push(answer[pos..pos + blob_len].to_vec());
+        pos += blob_len;
+        let comment_len = u32::from_be_bytes(answer[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4 + comment_len;
+    }
+    assert!(blobs.contains(&first_key), "Missing first backend's key blob");
+    assert!(blobs.contains(&second_key), "Missing second backend's key blob");
+
+    // Sign with the first backend's key: only that backend should have answered.
+    let mut sign_request = vec![SSH_AGENTC_SIGN_REQUEST];
+    push_string(&mut sign_request, &first_key);
+    push_string(&mut sign_request, b"some data to sign");
+    write_frame(&mut stream, &sign_request).expect("Failed to send sign request");
+    let reply = read_frame(&mut stream)
+        .expect("Failed to read sign reply")
+        .expect("Connection closed before sign reply");
+    assert_eq!(reply[0], SSH_AGENT_SIGN_RESPONSE, "Expected a sign response for a known key");
+    assert!(
+        reply.ends_with(b"first@example.com"),
+        "Sign response should come from the backend that owns the key"
+    );
+
+    // Sign with an unknown key: expect a failure.
+    let mut bad_request = vec![SSH_AGENTC_SIGN_REQUEST];
+    push_string(&mut bad_request, b"unknown-key-blob");
+    push_string(&mut bad_request, b"some data to sign");
+    write_frame(&mut stream, &bad_request).expect("Failed to send sign request");
+    let reply = read_frame(&mut stream)
+        .expect("Failed to read failure reply")
+        .expect("Connection closed before failure reply");
+    assert_eq!(reply[0], SSH_AGENT_FAILURE, "Expected a failure for an unknown key");
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    child.wait().expect("Failed to wait for child");
+}
+
+#[test]
+fn test_failover_mode() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+
+    let first_dir = temp_dir.path().join("ssh-first");
+    std::fs::create_dir(&first_dir).expect("Failed to create first subdir");
+    let first_socket = first_dir.join("agent.first");
+    let first_key = b"key-blob-first".to_vec();
+    let first_comment = b"first@example.com".to_vec();
+    let mut first_backend = Backend::new(
+        first_socket.clone(),
+        BackendType::Agent { key_blob: first_key.clone(), comment: first_comment },
+    );
+    first_backend.start();
+
+    let second_dir = temp_dir.path().join("ssh-second");
+    std::fs::create_dir(&second_dir).expect("Failed to create second subdir");
+    let second_socket = second_dir.join("agent.second");
+    let second_key = b"key-blob-second".to_vec();
+    let second_comment = b"second@example.com".to_vec();
+    // Not started yet: the switcher should only find it once `first_backend` is stopped and
+    // `reselect` runs again.
+    let mut second_backend = Backend::new(
+        second_socket.clone(),
+        BackendType::Agent { key_blob: second_key.clone(), comment: second_comment },
+    );
+
+    let switcher_socket = temp_dir.path().join("switcher.sock");
+
+    let mut child = Command::new(binary_path())
+        .arg("--socket-path")
+        .arg(&switcher_socket)
+        .arg("--agents-dirs")
+        .arg(temp_dir.path())
+        .arg("--failover")
+        .arg("--failover-read-timeout")
+        .arg("200")
+        .spawn()
+        .expect("Failed to start ssh-agent-switcher");
+
+    assert!(
+        wait_for_path(&switcher_socket, Duration::from_secs(5)),
+        "Switcher socket was not created"
+    );
+    assert!(wait_for_path(&first_socket, Duration::from_secs(2)), "First backend socket was not created");
+
+    let mut stream = UnixStream::connect(&switcher_socket).expect("Failed to connect");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // First request goes to the only live backend.
+    write_frame(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]).expect("Failed to send list request");
+    let answer = read_frame(&mut stream)
+        .expect("Failed to read identities answer")
+        .expect("Connection closed before identities answer");
+    assert_eq!(answer[0], SSH_AGENT_IDENTITIES_ANSWER, "Expected an identities answer");
+    let blob_len = u32::from_be_bytes(answer[5..9].try_into().unwrap()) as usize;
+    assert_eq!(&answer[9..9 + blob_len], first_key.as_slice(), "Expected the first backend's key");
+
+    // Kill the active backend and bring up the other one: the switcher should transparently
+    // reselect to it without the client's connection ever erroring out.
+    first_backend.stop();
+    second_backend.start();
+    assert!(wait_for_path(&second_socket, Duration::from_secs(2)), "Second backend socket was not created");
+
+    write_frame(&mut stream, &[SSH_AGENTC_REQUEST_IDENTITIES]).expect("Failed to send list request");
+    let answer = read_frame(&mut stream)
+        .expect("Failed to read identities answer after failover")
+        .expect("Connection closed before identities answer after failover");
+    assert_eq!(answer[0], SSH_AGENT_IDENTITIES_ANSWER, "Expected an identities answer after failover");
+    let blob_len = u32::from_be_bytes(answer[5..9].try_into().unwrap()) as usize;
+    assert_eq!(&answer[9..9 + blob_len], second_key.as_slice(), "Expected the second backend's key");
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    child.wait().expect("Failed to wait for child");
+}